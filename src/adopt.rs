@@ -0,0 +1,206 @@
+use crate::audit::AuditEvent;
+use crate::{is_locked, user_id, Bot, Party, PARTY_PREFIX};
+use cmd::Args;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Instant;
+use tracing::{info, warn};
+
+impl Bot {
+    // Admin commands, so they go by Manage Channels rather than the whitelist role.
+    pub fn may_manage_channels(&self, guild: GuildId, message: &Message) -> bool {
+        let role_cache = self.create_chan_role_cache.read();
+        message.member.as_ref().map_or(false, |m| m.roles.iter().any(|r| role_cache.contains(r)))
+            || self.guild_owner_cache.read().get(&guild) == Some(&message.author.id)
+    }
+
+    // `/party adopt <category> [owner=@user]` turns an existing category and everything in it
+    // into a party, owned by whoever's named or else whoever asked.
+    pub fn adopt_command(&self, ctx: &Context, message: &Message, guild: GuildId) {
+        if !self.may_manage_channels(guild, message) {
+            let _ = message.reply(ctx, "You need to be able to manage channels to adopt a category.");
+            return;
+        }
+        let args = match Args::parse(&message.content[6..]) {
+            Ok(args) => args,
+            Err(_) => {
+                let _ = message.reply(ctx, "Failed to parse command!");
+                return;
+            }
+        };
+        let cat = match args.args.get(1).and_then(|cat| cat.parse::<ChannelId>().ok()) {
+            Some(cat) => cat,
+            None => {
+                let _ = message.reply(ctx, "Usage: `/party adopt <category> [owner=@user]`");
+                return;
+            }
+        };
+        let owner = match args.kwargs.get("owner") {
+            Some(owner) => match owner.parse::<UserId>() {
+                Ok(owner) => owner,
+                Err(_) => {
+                    let _ = message.reply(ctx, "That owner isn't a user.");
+                    return;
+                }
+            },
+            None => message.author.id,
+        };
+        if self.owner_cache.read().contains_right(&(owner, guild, false)) {
+            let _ = message.reply(ctx, "They already have a party.");
+            return;
+        }
+        let channels = match guild.channels(ctx) {
+            Ok(channels) => channels,
+            Err(e) => {
+                warn!(guild = %guild, error = ?e, "Failed to get channels");
+                let _ = message.reply(ctx, "Couldn't get the server's channels.");
+                return;
+            }
+        };
+        let category = match channels.get(&cat) {
+            Some(category) if category.kind == ChannelType::Category => category,
+            _ => {
+                let _ = message.reply(ctx, "That isn't a category in this server.");
+                return;
+            }
+        };
+        let mut children = channels.values()
+            .filter(|c| c.category_id == Some(cat))
+            .collect::<Vec<_>>();
+        children.sort_by_key(|c| (c.position, c.id));
+        let voice = children.iter().filter(|c| c.kind == ChannelType::Voice).map(|c| c.id).collect::<Vec<_>>();
+        let text = children.iter().filter(|c| c.kind == ChannelType::Text).map(|c| c.id).collect::<Vec<_>>();
+        if voice.is_empty() && text.is_empty() {
+            let _ = message.reply(ctx, "There's nothing in that category to adopt.");
+            return;
+        }
+        let limit = children.iter()
+            .find(|c| c.kind == ChannelType::Voice)
+            .and_then(|vc| vc.user_limit)
+            .filter(|&limit| limit > 0)
+            .map(|limit| limit as u32);
+        let name = category.name.strip_prefix(PARTY_PREFIX).unwrap_or(&category.name).to_string();
+        let claimed = |party_cache: &BTreeMap<ChannelId, Party>| party_cache.iter().any(|(&key, party)| {
+            key == cat || party.voice.iter().chain(&party.text).any(|chan| voice.contains(chan) || text.contains(chan))
+        });
+        // Checked before anything's changed on Discord, so a refusal leaves the category as it was.
+        if claimed(&self.party_cache.read()) {
+            let _ = message.reply(ctx, "That's already a party.");
+            return;
+        }
+
+        // Without the prefix the next update to the category would read as someone renaming it
+        // away from being a party. It goes on first so every later event already has it.
+        if !category.name.starts_with(PARTY_PREFIX) {
+            if let Err(e) = self.edit_channel(ctx, cat, |c| c.name(format!("{}{}", PARTY_PREFIX, name))) {
+                warn!(party = %cat, error = ?e, "Failed to rename adopted category");
+                let _ = message.reply(ctx, "Couldn't rename that category to mark it as a party.");
+                return;
+            }
+        }
+
+        // The owner gets what they'd have had if the bot had made it, and the bot makes sure it
+        // can still manage the channels.
+        for &user in &[owner, user_id()] {
+            let res = self.set_permission(ctx, cat, &PermissionOverwrite {
+                allow: self.perms_creator,
+                deny: Permissions::empty(),
+                kind: PermissionOverwriteType::Member(user),
+            });
+            if let Err(e) = res {
+                warn!(party = %cat, error = ?e, "Failed to set permissions on adopted category");
+                let _ = message.reply(ctx, "Couldn't set the owner's permissions on that category.");
+                return;
+            }
+        }
+
+        {
+            let mut cache = self.category_cache.write();
+            let mut party_cache = self.party_cache.write();
+            // Again, in case it was taken while the channels were being changed.
+            if claimed(&party_cache) {
+                drop(party_cache);
+                drop(cache);
+                let _ = message.reply(ctx, "That's already a party.");
+                return;
+            }
+            for &vc in &voice {
+                cache.insert(vc, cat);
+            }
+            party_cache.insert(cat, Party {
+                guild,
+                name: name.clone(),
+                has_category: true,
+                squads: voice.len(),
+                last_active: if voice.is_empty() { Some(Instant::now()) } else { None },
+                voice,
+                text,
+                thread: None,
+                limit,
+                overflow_count: 0,
+                co_owners: BTreeSet::new(),
+                granted: BTreeSet::new(),
+                locked: is_locked(guild, category),
+                panel: None,
+                pinned: false,
+            });
+        }
+        self.owner_cache.write().insert(cat, (owner, guild, false));
+        info!(party = %cat, owner = %owner, by = %message.author.id, "Adopted category");
+        self.audit(ctx, guild, Some((cat, &name)), AuditEvent::Adopted { owner, by: message.author.id });
+        self.post_panel(ctx, cat);
+        let _ = message.reply(ctx, format!(
+            "{} is now a party owned by {}. It'll be cleaned up like any other once everyone's left.",
+            name,
+            owner.mention()
+        ));
+    }
+
+    // For admin commands that take an optional category: the party it names, or else the one
+    // whose voice channel the caller is in.
+    pub fn party_from_command(&self, message: &Message, guild: GuildId) -> Result<ChannelId, &'static str> {
+        let key = match message.content[6..].split_whitespace().nth(1) {
+            Some(cat) => cat.parse::<ChannelId>().ok(),
+            None => self.voice_channels.read().get(&message.author.id)
+                .and_then(|vc| self.category_cache.read().get(vc).copied()),
+        };
+        let is_party = key.map_or(false, |key| {
+            self.party_cache.read().get(&key).map_or(false, |party| party.guild == guild)
+        });
+        match key {
+            Some(key) if is_party => Ok(key),
+            _ => Err("That isn't a party. Name its category, or run this from its voice channel."),
+        }
+    }
+
+    // `/party release [category]` hands a party back to the server: the channels stay, but the
+    // bot forgets about them and never cleans them up. Without a category it's the party the
+    // caller is in. The prefix comes off too, or purge and --import-legacy would take it for a
+    // party the state file lost.
+    pub fn release_command(&self, ctx: &Context, message: &Message, guild: GuildId) {
+        if !self.may_manage_channels(guild, message) {
+            let _ = message.reply(ctx, "You need to be able to manage channels to release a party.");
+            return;
+        }
+        let key = match self.party_from_command(message, guild) {
+            Ok(key) => key,
+            Err(why) => {
+                let _ = message.reply(ctx, why);
+                return;
+            }
+        };
+        let reason = format!("Released by {}", message.author.mention());
+        if let Some(party) = self.release_party(ctx, key, &reason) {
+            if let Err(e) = self.edit_channel(ctx, key, |c| c.name(&party.name)) {
+                warn!(party = %key, error = ?e, "Failed to take the prefix off a released party");
+                let _ = message.reply(ctx, format!(
+                    "Released {}, but couldn't rename it. Take the `{}` off its name yourself, or it'll look like a party again.",
+                    party.name, PARTY_PREFIX.trim_end()
+                ));
+                return;
+            }
+            let _ = message.reply(ctx, format!("Released {}. Its channels are yours to look after now.", party.name));
+        }
+    }
+}
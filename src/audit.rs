@@ -0,0 +1,142 @@
+use crate::Bot;
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::utils::Colour;
+use tracing::warn;
+
+// Text channels with this in their topic get an entry for everything the bot does to parties.
+pub const LOG_MARKER: &str = "+#log";
+
+pub enum AuditEvent {
+    Created { owner: UserId, members: Vec<UserId> },
+    Granted { user: UserId, via: &'static str, by: Option<UserId> },
+    OwnerChanged { from: Option<UserId>, to: UserId, by: UserId },
+    Failed { stage: &'static str, error: String },
+    Cleanup { reason: String },
+    Changed { change: String }, // Someone other than the bot changed the party's channels
+    Released { reason: String }, // The bot has stopped managing the party but left it standing
+    Adopted { owner: UserId, by: UserId }, // An existing category was made into a party
+    Pinned { pinned: bool, by: UserId },
+}
+
+fn user(user: UserId) -> String {
+    format!("<@{}> (`{}`)", user, user)
+}
+
+impl AuditEvent {
+    fn title(&self) -> &'static str {
+        match self {
+            AuditEvent::Created { .. } => "Party created",
+            AuditEvent::Granted { .. } => "Access granted",
+            AuditEvent::OwnerChanged { .. } => "Ownership changed",
+            AuditEvent::Failed { .. } => "Discord API call failed",
+            AuditEvent::Cleanup { .. } => "Party cleaned up",
+            AuditEvent::Changed { .. } => "Party changed outside the bot",
+            AuditEvent::Released { .. } => "Party released",
+            AuditEvent::Adopted { .. } => "Category adopted",
+            AuditEvent::Pinned { pinned: true, .. } => "Party pinned",
+            AuditEvent::Pinned { pinned: false, .. } => "Party unpinned",
+        }
+    }
+
+    fn colour(&self) -> Colour {
+        match self {
+            AuditEvent::Created { .. } | AuditEvent::Adopted { .. } => Colour::DARK_GREEN,
+            AuditEvent::Granted { .. } | AuditEvent::OwnerChanged { .. } | AuditEvent::Pinned { .. } => Colour::BLUE,
+            AuditEvent::Failed { .. } => Colour::RED,
+            AuditEvent::Cleanup { .. } | AuditEvent::Released { .. } => Colour::LIGHT_GREY,
+            AuditEvent::Changed { .. } => Colour::ORANGE,
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        match self {
+            AuditEvent::Created { owner, members } => vec![
+                ("Owner", user(*owner)),
+                ("Members", if members.is_empty() {
+                    "none".to_string()
+                } else {
+                    members.iter().map(|&m| user(m)).collect::<Vec<_>>().join("\n")
+                }),
+            ],
+            AuditEvent::Granted { user: target, via, by } => {
+                let mut fields = vec![("User", user(*target)), ("Via", via.to_string())];
+                if let Some(by) = by {
+                    fields.push(("By", user(*by)));
+                }
+                fields
+            }
+            AuditEvent::OwnerChanged { from, to, by } => vec![
+                ("From", from.map_or_else(|| "nobody".to_string(), user)),
+                ("To", user(*to)),
+                ("By", user(*by)),
+            ],
+            AuditEvent::Failed { stage, error } => vec![
+                ("Stage", stage.to_string()),
+                ("Error", format!("```{}```", error.chars().take(1000).collect::<String>())),
+            ],
+            AuditEvent::Cleanup { reason } | AuditEvent::Released { reason } => vec![("Reason", reason.clone())],
+            AuditEvent::Changed { change } => vec![("Change", change.clone())],
+            AuditEvent::Adopted { owner, by } => vec![("Owner", user(*owner)), ("By", user(*by))],
+            AuditEvent::Pinned { by, .. } => vec![("By", user(*by))],
+        }
+    }
+}
+
+impl Bot {
+    pub fn update_log_channel(&self, channel: &GuildChannel) {
+        if channel.kind != ChannelType::Text {
+            return;
+        }
+        let mut log_channels = self.log_channel_cache.write();
+        if channel.topic.as_deref().map_or(false, |t| t.contains(LOG_MARKER)) {
+            log_channels.insert(channel.guild_id, channel.id);
+        } else if log_channels.get(&channel.guild_id) == Some(&channel.id) {
+            log_channels.remove(&channel.guild_id);
+        }
+    }
+
+    // `party` is the party's key and name, when there is one. Failing to log isn't worth
+    // more than a warning.
+    pub fn audit(&self, http: impl AsRef<Http>, guild: GuildId, party: Option<(ChannelId, &str)>, event: AuditEvent) {
+        // Everything that's audited is counted too, whether or not the guild has a log channel.
+        match event {
+            AuditEvent::Created { .. } => self.metrics.parties_created.inc(),
+            AuditEvent::Failed { stage, .. } => self.metrics.party_failures.with_label_values(&[stage]).inc(),
+            AuditEvent::Cleanup { .. } => self.metrics.parties_deleted.inc(),
+            _ => {}
+        }
+        let log_channel = match self.log_channel_cache.read().get(&guild) {
+            Some(&log_channel) => log_channel,
+            None => return,
+        };
+        let res = log_channel.send_message(&http, |m| {
+            m.embed(|e| {
+                if self.dry_run.is_enabled() {
+                    e.title(format!("[dry run] {}", event.title()));
+                } else {
+                    e.title(event.title());
+                }
+                e.colour(event.colour());
+                if let Some((key, name)) = party {
+                    e.field("Party", format!("{} (`{}`)", name, key), false);
+                }
+                for (name, value) in event.fields() {
+                    e.field(name, value, true);
+                }
+                e.footer(|f| f.text(format!("Guild {}", guild)))
+            })
+        });
+        if let Err(e) = res {
+            warn!(guild = %guild, channel = %log_channel, error = ?e, "Failed to write to the audit log");
+        }
+    }
+
+    // Same as audit, but looks the party up. Don't call it while holding party_cache.
+    pub fn audit_party(&self, http: impl AsRef<Http>, key: ChannelId, event: AuditEvent) {
+        let party = self.party_cache.read().get(&key).map(|party| (party.guild, party.name.clone()));
+        if let Some((guild, name)) = party {
+            self.audit(http, guild, Some((key, &name)), event);
+        }
+    }
+}
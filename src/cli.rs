@@ -0,0 +1,269 @@
+use crate::state::{self, SavedState};
+use crate::token::{self, Token, TokenError};
+use crate::{find_parties, metrics, PARTY_PREFIX};
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+pub const USAGE: &str = "\
+Usage: coordinator-bot [command] [options]
+
+Commands:
+    run                     Run the bot (the default)
+    check-config            Check the token, state file and environment without connecting
+    purge --guild <id>      Delete party channels in a guild that the state file doesn't know
+                            about; stop the bot first
+    export-state [path]     Write the saved state to a file, or stdout
+    import-state <path>     Replace the saved state with a file; stop the bot first
+
+Options:
+    --token-file <path>     Read the token from a file only its owner can read
+    --token-stdin           Read the token from stdin
+    --dry-run               Say what would change without changing anything
+    --import-legacy         Take on parties named with the '+# ' prefix that the state file
+                            doesn't know about, as older versions found them
+
+The token can also come from DISCORD_TOKEN. STATE_FILE, METRICS_ADDR, RUST_LOG and LOG_FORMAT
+configure the rest.";
+
+pub enum Command {
+    Run,
+    CheckConfig,
+    Purge(GuildId),
+    ExportState(Option<PathBuf>),
+    ImportState(PathBuf),
+}
+
+pub struct Options {
+    pub command: Command,
+    pub dry_run: bool,
+    pub import_legacy: bool,
+    token_file: Option<PathBuf>,
+    token_stdin: bool,
+}
+
+// The token used to be the first argument, so old invocations would have these messages print
+// it straight into the logs.
+fn redact(arg: &str) -> String {
+    if token::validate(arg.strip_prefix("Bot ").unwrap_or(arg)).is_ok() {
+        "<something that looks like a token; set DISCORD_TOKEN or use --token-file instead>".to_string()
+    } else {
+        arg.to_string()
+    }
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut positional = Vec::new();
+        let mut dry_run = false;
+        let mut import_legacy = false;
+        let mut token_file = None;
+        let mut token_stdin = false;
+        let mut guild = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" => dry_run = true,
+                "--import-legacy" => import_legacy = true,
+                "--token-stdin" => token_stdin = true,
+                "--token-file" => match args.next() {
+                    Some(path) => token_file = Some(PathBuf::from(path)),
+                    None => return Err("--token-file needs a path".to_string()),
+                },
+                "--guild" => match args.next().and_then(|id| id.parse::<u64>().ok()) {
+                    Some(id) => guild = Some(GuildId(id)),
+                    None => return Err("--guild needs a guild ID".to_string()),
+                },
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+        let command = match positional.next().as_deref() {
+            None | Some("run") => Command::Run,
+            Some("check-config") => Command::CheckConfig,
+            Some("purge") => Command::Purge(guild.ok_or("purge needs --guild <id>")?),
+            Some("export-state") => Command::ExportState(positional.next().map(PathBuf::from)),
+            Some("import-state") => match positional.next() {
+                Some(path) => Command::ImportState(PathBuf::from(path)),
+                None => return Err("import-state needs a path".to_string()),
+            },
+            Some(other) => return Err(format!("Unknown command {}\n\n{}", redact(other), USAGE)),
+        };
+        if let Some(extra) = positional.next() {
+            return Err(format!("Unexpected argument {}", redact(&extra)));
+        }
+        Ok(Options { command, dry_run, import_legacy, token_file, token_stdin })
+    }
+
+    pub fn load_token(&self) -> Result<Token, TokenError> {
+        match self.token_file {
+            Some(ref path) => token::from_file(path),
+            None if self.token_stdin => token::from_stdin(),
+            None => token::from_env(),
+        }
+    }
+}
+
+pub fn check_config(options: &Options) -> Result<(), String> {
+    let mut problems = Vec::new();
+    if let Err(e) = options.load_token() {
+        problems.push(e.to_string());
+    }
+    let state_path = state::path();
+    match SavedState::load(&state_path) {
+        Ok(saved) => println!("State file {} holds {} parties", state_path.display(), saved.party_count()),
+        Err(e) => problems.push(format!("Can't read the state file {}: {}", state_path.display(), e)),
+    }
+    if let Err(e) = metrics::addr().parse::<SocketAddr>() {
+        problems.push(format!("METRICS_ADDR isn't an address: {}", e));
+    }
+    if let Ok(filter) = std::env::var("RUST_LOG") {
+        if let Err(e) = EnvFilter::try_new(filter) {
+            problems.push(format!("RUST_LOG isn't a valid filter: {}", e));
+        }
+    }
+    match std::env::var("LOG_FORMAT").ok().as_deref() {
+        None | Some("json") | Some("text") => {}
+        Some(other) => problems.push(format!("LOG_FORMAT should be json or text, not {}", other)),
+    }
+    if problems.is_empty() {
+        println!("Configuration looks good.");
+        Ok(())
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+// Anything matching the party naming that the state file has no record of is something the bot
+// lost track of, usually because it died mid-creation.
+pub fn purge(options: &Options, guild: GuildId) -> Result<(), String> {
+    let token = options.load_token().map_err(|e| e.to_string())?;
+    // A missing state file would make every live party look lost, so unlike the bot, purge
+    // doesn't take that as empty.
+    let state_path = state::path();
+    if !state_path.exists() {
+        return Err(format!(
+            "There's no state file at {}; set STATE_FILE or run from the bot's working directory",
+            state_path.display()
+        ));
+    }
+    let saved = SavedState::load(&state_path).map_err(|e| format!("Can't read the state file: {}", e))?;
+    let http = Http::new_with_token(token.expose());
+    let channels = guild.channels(&http).map_err(|e| format!("Failed to get channels: {:?}", e))?;
+    let parties = find_parties(guild, channels.values());
+    let mut doomed = Vec::new();
+    for (key, party) in parties {
+        if saved.contains(key) {
+            continue;
+        }
+        doomed.extend(party.voice.iter().chain(&party.text).copied());
+        if party.has_category {
+            doomed.push(key);
+        }
+    }
+    // find_parties skips categories with nothing in them, but an empty category is just what's
+    // left when creation dies straight after making it.
+    let empty = channels.values().filter(|c| {
+        c.kind == ChannelType::Category
+            && c.name.starts_with(PARTY_PREFIX)
+            && !saved.contains(c.id)
+            && !channels.values().any(|child| child.category_id == Some(c.id))
+    });
+    doomed.extend(empty.map(|c| c.id));
+    let mut failed = false;
+    for chan in doomed {
+        let name = channels.get(&chan).map_or("?", |c| c.name.as_str());
+        if options.dry_run {
+            println!("Would delete {} ({})", name, chan);
+        } else if let Err(e) = chan.delete(&http) {
+            println!("Failed to delete {} ({}): {:?}", name, chan, e);
+            failed = true;
+        } else {
+            info!(channel = %chan, "Purged");
+            println!("Deleted {} ({})", name, chan);
+        }
+    }
+    if failed {
+        Err("Some channels couldn't be deleted".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn export_state(options: &Options, dest: Option<&Path>) -> Result<(), String> {
+    let saved = SavedState::load(&state::path()).map_err(|e| format!("Can't read the state file: {}", e))?;
+    let json = serde_json::to_vec_pretty(&saved).map_err(|e| e.to_string())?;
+    match dest {
+        Some(_) if options.dry_run => {
+            println!("Would export {} parties", saved.party_count());
+            Ok(())
+        }
+        Some(dest) => fs::write(dest, json).map_err(|e| format!("Failed to write {}: {}", dest.display(), e)),
+        None => io::stdout().write_all(&json).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn import_state(options: &Options, src: &Path) -> Result<(), String> {
+    // Not SavedState::load, which takes a missing file as an empty one: a typo here would wipe
+    // out the real state file.
+    let bytes = fs::read(src).map_err(|e| format!("Can't read {}: {}", src.display(), e))?;
+    let saved: SavedState = serde_json::from_slice(&bytes).map_err(|e| format!("Can't read {}: {}", src.display(), e))?;
+    let state_path = state::path();
+    if options.dry_run {
+        println!("Would import {} parties into {}", saved.party_count(), state_path.display());
+        return Ok(());
+    }
+    saved.save(&state_path).map_err(|e| format!("Failed to write {}: {}", state_path.display(), e))?;
+    println!("Imported {} parties into {}", saved.party_count(), state_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn purge_needs_a_guild() {
+        match parse(&["purge"]) {
+            Err(e) => assert!(e.contains("--guild")),
+            Ok(_) => panic!("purge without --guild was accepted"),
+        }
+        assert!(parse(&["purge", "--guild", "not-a-number"]).is_err());
+        let options = parse(&["purge", "--guild", "1234", "--dry-run"]).unwrap();
+        assert!(matches!(options.command, Command::Purge(GuildId(1234))));
+        assert!(options.dry_run);
+    }
+
+    #[test]
+    fn run_is_the_default() {
+        assert!(matches!(parse(&[]).unwrap().command, Command::Run));
+        assert!(matches!(parse(&["--import-legacy"]).unwrap().command, Command::Run));
+    }
+
+    #[test]
+    fn import_state_needs_a_path() {
+        assert!(parse(&["import-state"]).is_err());
+        assert!(matches!(parse(&["import-state", "old.json"]).unwrap().command, Command::ImportState(_)));
+    }
+
+    #[test]
+    fn tokens_passed_as_arguments_arent_echoed() {
+        let token = "MTIzNDU2Nzg5MDEyMzQ1Njc4.AbCdEf.abc-DEF_ghi123";
+        for args in &[vec![token], vec!["run", token]] {
+            match parse(args) {
+                Err(e) => assert!(!e.contains(token)),
+                Ok(_) => panic!("a stray token was accepted"),
+            }
+        }
+    }
+}
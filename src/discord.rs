@@ -0,0 +1,182 @@
+use crate::retry::{self, Failure, Op};
+use crate::Bot;
+use serenity::builder::{CreateChannel, EditChannel};
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::info;
+
+// Every call that changes something on Discord goes through here, so dry-run mode can log it
+// instead. The caches still update as if it worked, which is the point: you get to see what
+// the bot would go on to do.
+//
+// Deletes, edits, moves and permission changes that fail in a way worth retrying get handed to
+// the retry queue and reported as done; only failures that won't go away come back as errors.
+pub struct DryRun {
+    enabled: bool,
+    // Counts down from the top so made-up IDs can't run into real snowflakes.
+    next_id: AtomicU64,
+}
+
+impl DryRun {
+    pub fn new(enabled: bool) -> DryRun {
+        DryRun {
+            enabled,
+            next_id: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn fake_id(&self) -> ChannelId {
+        ChannelId(self.next_id.fetch_sub(1, Ordering::SeqCst))
+    }
+}
+
+impl Bot {
+    pub fn create_channel(
+        &self,
+        http: impl AsRef<Http>,
+        guild: GuildId,
+        f: impl FnOnce(&mut CreateChannel) -> &mut CreateChannel,
+    ) -> serenity::Result<ChannelId> {
+        if self.dry_run.is_enabled() {
+            let mut builder = CreateChannel::default();
+            f(&mut builder);
+            let id = self.dry_run.fake_id();
+            info!(dry_run = true, guild = %guild, channel = %id, options = ?builder.0, "Would create channel");
+            return Ok(id);
+        }
+        self.metrics.timed("create channel", || guild.create_channel(&http, f)).map(|chan| chan.id)
+    }
+
+    pub fn edit_channel(
+        &self,
+        http: impl AsRef<Http>,
+        chan: ChannelId,
+        f: impl FnOnce(&mut EditChannel) -> &mut EditChannel,
+    ) -> serenity::Result<()> {
+        let mut builder = EditChannel::default();
+        f(&mut builder);
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, channel = %chan, changes = ?builder.0, "Would edit channel");
+            return Ok(());
+        }
+        let changes = serenity::utils::hashmap_to_json_map(builder.0);
+        match self.metrics.timed("edit channel", || http.as_ref().edit_channel(chan.0, &changes)) {
+            Ok(_) => Ok(()),
+            Err(e) => self.retry_or(Op::EditChannel(chan, changes), e),
+        }
+    }
+
+    fn retry_or(&self, op: Op, e: serenity::Error) -> serenity::Result<()> {
+        if retry::is_retryable(&e) {
+            self.retry_later(op, Failure::from(e));
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }
+
+    pub fn delete_channel(&self, http: impl AsRef<Http>, chan: ChannelId) -> serenity::Result<()> {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, channel = %chan, "Would delete channel");
+            return Ok(());
+        }
+        match self.metrics.timed("delete channel", || chan.delete(&http)) {
+            Ok(_) => Ok(()),
+            Err(e) => self.retry_or(Op::DeleteChannel(chan), e),
+        }
+    }
+
+    pub fn move_member(&self, http: impl AsRef<Http>, guild: GuildId, user: UserId, chan: ChannelId) -> serenity::Result<()> {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, guild = %guild, user = %user, channel = %chan, "Would move member");
+            return Ok(());
+        }
+        match self.metrics.timed("move member", || guild.move_member(&http, user, chan)) {
+            Ok(_) => Ok(()),
+            Err(e) => self.retry_or(Op::MoveMember(guild, user, chan), e),
+        }
+    }
+
+    pub fn set_permission(&self, http: impl AsRef<Http>, chan: ChannelId, overwrite: &PermissionOverwrite) -> serenity::Result<()> {
+        if self.dry_run.is_enabled() {
+            info!(
+                dry_run = true,
+                channel = %chan,
+                target = ?overwrite.kind,
+                allow = ?overwrite.allow,
+                deny = ?overwrite.deny,
+                "Would set permission"
+            );
+            return Ok(());
+        }
+        match self.metrics.timed("create permission", || chan.create_permission(&http, overwrite)) {
+            Ok(()) => Ok(()),
+            Err(e) => self.retry_or(Op::SetPermission(chan, overwrite.clone()), e),
+        }
+    }
+
+    // The LFG board and control panels. Those aren't worth retrying: the next refresh puts them
+    // right anyway.
+    pub fn edit_message(&self, http: impl AsRef<Http>, chan: ChannelId, message: MessageId, content: String) {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, channel = %chan, message = %message, "Would edit message");
+            return;
+        }
+        let _ = self.metrics.timed("edit message", || chan.edit_message(&http, message, |m| m.content(content)));
+    }
+
+    pub fn delete_message(&self, http: impl AsRef<Http>, chan: ChannelId, message: MessageId) {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, channel = %chan, message = %message, "Would delete message");
+            return;
+        }
+        let _ = self.metrics.timed("delete message", || chan.delete_message(&http, message));
+    }
+
+    // Takes a reaction back off so the button can be pressed again.
+    pub fn delete_reaction(&self, http: impl AsRef<Http>, reaction: &Reaction) {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, channel = %reaction.channel_id, message = %reaction.message_id, "Would remove reaction");
+            return;
+        }
+        let _ = self.metrics.timed("delete reaction", || reaction.channel_id.delete_reaction(
+            &http,
+            reaction.message_id,
+            Some(reaction.user_id),
+            reaction.emoji.clone(),
+        ));
+    }
+
+    pub fn create_thread(&self, parent: ChannelId, name: &str) -> Option<ChannelId> {
+        if self.dry_run.is_enabled() {
+            let id = self.dry_run.fake_id();
+            info!(dry_run = true, channel = %parent, thread = %id, name, "Would create thread");
+            return Some(id);
+        }
+        self.metrics.timed("create thread", || self.threads.create_private(parent, name))
+    }
+
+    pub fn add_thread_member(&self, thread: ChannelId, user: UserId) -> bool {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, thread = %thread, user = %user, "Would add user to thread");
+            return true;
+        }
+        self.metrics.timed("add thread member", || self.threads.add_member(thread, user)).is_ok()
+    }
+
+    pub fn archive_thread(&self, thread: ChannelId) -> bool {
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, thread = %thread, "Would archive thread");
+            return true;
+        }
+        match self.metrics.timed("archive thread", || self.threads.archive(thread)) {
+            Ok(()) => true,
+            Err(e) => self.retry_later(Op::ArchiveThread(thread), Failure::from(e)),
+        }
+    }
+}
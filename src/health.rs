@@ -0,0 +1,60 @@
+use crate::Bot;
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// The cleanup thread beats once a minute, so this allows for a couple of slow iterations.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(180);
+
+pub struct Health {
+    heartbeat: RwLock<Instant>,
+    pub gateway_connected: AtomicBool,
+    pub ready_processed: AtomicBool,
+    pub state_loaded: AtomicBool,
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health {
+            heartbeat: RwLock::new(Instant::now()),
+            gateway_connected: AtomicBool::new(false),
+            ready_processed: AtomicBool::new(false),
+            state_loaded: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Health {
+    pub fn beat(&self) {
+        *self.heartbeat.write() = Instant::now();
+    }
+}
+
+impl Bot {
+    // Alive as long as the cleanup thread is still going round.
+    pub fn healthz(&self) -> (u16, String) {
+        let since = self.health.heartbeat.read().elapsed();
+        if since < HEARTBEAT_TIMEOUT {
+            (200, "ok".to_string())
+        } else {
+            (503, format!("cleanup thread last seen {}s ago", since.as_secs()))
+        }
+    }
+
+    pub fn readyz(&self) -> (u16, String) {
+        let checks = [
+            ("gateway connected", &self.health.gateway_connected),
+            ("ready processed", &self.health.ready_processed),
+            ("state loaded", &self.health.state_loaded),
+        ];
+        let failing = checks.iter()
+            .filter(|(_, flag)| !flag.load(Ordering::SeqCst))
+            .map(|&(name, _)| name)
+            .collect::<Vec<_>>();
+        if failing.is_empty() {
+            (200, "ok".to_string())
+        } else {
+            (503, format!("not yet: {}", failing.join(", ")))
+        }
+    }
+}
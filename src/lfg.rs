@@ -0,0 +1,303 @@
+use crate::voice::{mention_list, VoiceMembers};
+use crate::{user_id, Bot, PartyLayout};
+use cmd::Args;
+use serde::{Deserialize, Serialize};
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Text channels with this in their topic become the guild's LFG board.
+// Channel names can't hold the "+#" that the whitelist role uses, so the topic it is.
+pub const LFG_MARKER: &str = "+#lfg";
+const JOIN_EMOJI: &str = "✅";
+// Queued users who haven't been matched after this long are dropped.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const MAX_QUEUE_SIZE: u8 = 25;
+
+// Saved with its party, so the post still gets taken down after a restart.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LfgEntry {
+    pub guild: GuildId,
+    pub name: String,
+    pub tag: String,
+    pub board: ChannelId,
+    pub message: MessageId,
+}
+
+impl LfgEntry {
+    // Capacity grows with overflow channels, so it's worked out by the caller.
+    fn render(&self, owner: Option<UserId>, used: u32, capacity: Option<u32>, members: &[UserId]) -> String {
+        let slots = match capacity {
+            Some(capacity) => format!("{}/{}", used, capacity),
+            None => format!("{}", used),
+        };
+        let owner = owner.map(|o| o.mention()).unwrap_or_else(|| "nobody".to_string());
+        format!(
+            "**{}** [{}]\nOwner: {}\nSlots: {}\nIn voice: {}\nReact with {} to join.",
+            self.name, self.tag, owner, slots, mention_list(members), JOIN_EMOJI
+        )
+    }
+}
+
+impl Bot {
+    pub fn update_lfg_channel(&self, channel: &GuildChannel) {
+        if channel.kind != ChannelType::Text {
+            return;
+        }
+        let mut lfg_channels = self.lfg_channel_cache.write();
+        let marked = channel.topic.as_ref().map_or(false, |t| t.contains(LFG_MARKER));
+        if marked {
+            if let Some(old) = lfg_channels.insert(channel.guild_id, channel.id) {
+                if old != channel.id {
+                    warn!(guild = %channel.guild_id, old = %old, new = %channel.id, "Guild has multiple LFG channels");
+                }
+            }
+        } else if lfg_channels.get(&channel.guild_id) == Some(&channel.id) {
+            lfg_channels.remove(&channel.guild_id);
+        }
+    }
+
+    // Returns (used, capacity) across all of a party's VCs.
+    fn lfg_slots(&self, cat: ChannelId, voice: &VoiceMembers) -> (u32, Option<u32>) {
+        match self.party_cache.read().get(&cat) {
+            Some(party) => (
+                party.headcount(voice),
+                party.limit.map(|limit| limit * party.voice.len() as u32),
+            ),
+            None => (0, None),
+        }
+    }
+
+    pub fn post_lfg_entry(&self, http: impl AsRef<Http>, guild: GuildId, cat: ChannelId, name: String, tag: String) {
+        let board = match self.lfg_channel_cache.read().get(&guild) {
+            Some(&board) => board,
+            None => return,
+        };
+        // There'd be no real message to hand back, so a dry run only says it would post.
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, party = %cat, channel = %board, "Would post LFG entry");
+            return;
+        }
+        let mut entry = LfgEntry {
+            guild,
+            name,
+            tag,
+            board,
+            message: MessageId(0),
+        };
+        let owner = self.owner_cache.read().get_by_left(&cat).map(|&(owner, ..)| owner);
+        let content = {
+            let voice = self.voice_members.read();
+            let (used, capacity) = self.lfg_slots(cat, &voice);
+            entry.render(owner, used, capacity, &self.party_members(cat, &voice))
+        };
+        let posted = board.send_message(&http, |m| {
+            m.content(content)
+                .reactions(vec![ReactionType::Unicode(JOIN_EMOJI.to_string())])
+        });
+        match posted {
+            Ok(message) => {
+                entry.message = message.id;
+                self.lfg_board.write().insert(cat, entry);
+            }
+            Err(e) => warn!(party = %cat, channel = %board, error = ?e, "Failed to post LFG entry"),
+        }
+    }
+
+    // Voice members are passed in because the caller is usually holding the lock on them.
+    pub fn refresh_lfg_entry(&self, http: impl AsRef<Http>, cat: ChannelId, voice: &VoiceMembers) {
+        let board = self.lfg_board.read();
+        if let Some(entry) = board.get(&cat) {
+            let owner = self.owner_cache.read().get_by_left(&cat).map(|&(owner, ..)| owner);
+            let (used, capacity) = self.lfg_slots(cat, voice);
+            let content = entry.render(owner, used, capacity, &self.party_members(cat, voice));
+            self.edit_message(&http, entry.board, entry.message, content);
+        }
+    }
+
+    pub fn remove_lfg_entry(&self, http: impl AsRef<Http>, cat: ChannelId) {
+        if let Some(entry) = self.lfg_board.write().remove(&cat) {
+            self.delete_message(&http, entry.board, entry.message);
+        }
+    }
+
+    pub fn handle_lfg_reaction(&self, http: impl AsRef<Http>, reaction: &Reaction) {
+        if reaction.user_id == user_id() {
+            return;
+        }
+        match reaction.emoji {
+            ReactionType::Unicode(ref emoji) if emoji == JOIN_EMOJI => {}
+            _ => return,
+        }
+        // Copy out what we need so the board isn't locked while we wait on voice_members.
+        let found = self.lfg_board.read().iter()
+            .find(|(_, entry)| entry.message == reaction.message_id)
+            .map(|(&cat, entry)| (cat, entry.guild));
+        let (category, guild) = match found {
+            Some(found) => found,
+            None => return,
+        };
+        self.delete_reaction(&http, reaction);
+        let (channels, limit) = match self.party_cache.read().get(&category) {
+            Some(party) => (party.voice.clone(), party.limit),
+            None => return,
+        };
+        // Overflow means there's normally room somewhere, but not always straight away.
+        let room = {
+            let voice = self.voice_members.read();
+            channels.into_iter().find(|&vc| limit.map_or(true, |limit| voice.count(guild, vc) < limit))
+        };
+        let res = self.grant_party_access(&http, category, reaction.user_id, "LFG board", None);
+        if res.is_err() {
+            warn!(party = %category, user = %reaction.user_id, error = ?res, "Failed to grant LFG join perms");
+            return;
+        }
+        // This fails if they aren't in voice, but they can still join by hand now.
+        if let Some(vc) = room {
+            let _ = self.move_member(&http, guild, reaction.user_id, vc);
+        }
+    }
+}
+
+impl Bot {
+    pub fn lfg_command(&self, ctx: &Context, message: &Message, guild: GuildId) {
+        let args = match Args::parse(&message.content[4..]) {
+            Ok(args) => args,
+            Err(_) => {
+                let _ = message.reply(ctx, "Failed to parse command!");
+                return;
+            }
+        };
+        let user = message.author.id;
+        match args.args.get(0).map(String::as_str) {
+            Some("leave") => {
+                if self.leave_lfg_queue(guild, user) {
+                    let _ = message.reply(ctx, "You've left the queue.");
+                } else {
+                    let _ = message.reply(ctx, "You aren't queued for anything.");
+                }
+                return;
+            }
+            Some(_) => {}
+            None => {
+                let _ = message.reply(ctx, "Usage: `/lfg <tag> <size>` or `/lfg leave`");
+                return;
+            }
+        }
+        let tag = args.args[0].to_lowercase().chars().take(32).collect::<String>();
+        let size = match args.args.get(1).and_then(|size| size.parse::<u8>().ok()) {
+            Some(size) if (2..=MAX_QUEUE_SIZE).contains(&size) => size,
+            _ => {
+                let _ = message.reply(ctx, format!("Size must be between 2 and {}.", MAX_QUEUE_SIZE));
+                return;
+            }
+        };
+
+        // Queueing doesn't cost anything, but getting matched makes somebody an owner,
+        // so the same limits as /party apply.
+        let since = self.ratelimit_cache.read().peek(&user).map(|&last| last.elapsed());
+        if self.owner_cache.read().contains_right(&(user, guild, false)) {
+            let _ = message.reply(ctx, "You already have a party! Disband it first.");
+            self.metrics.ratelimit_rejections.with_label_values(&["already owner"]).inc();
+            return;
+        } else if let Some(since) = since.filter(|&since| since < Duration::from_secs(300)) {
+            self.metrics.ratelimit_rejections.with_label_values(&["cooldown"]).inc();
+            let _ = message.reply(ctx, format!("You're making parties too fast! Wait another {} seconds", 300-since.as_secs()));
+            return;
+        }
+        if !self.may_create_party(guild, message) {
+            let _ = message.reply(ctx, "You do not have permission to use this command");
+            return;
+        }
+        if !self.voice_channels.read().contains_key(&user) {
+            let _ = message.reply(ctx, "Join a voice channel first so you can be moved when a match is found.");
+            return;
+        }
+
+        // You can only wait in one queue per guild.
+        self.leave_lfg_queue(guild, user);
+        let key = (guild, tag.clone(), size);
+        let players = {
+            let mut queues = self.lfg_queue.write();
+            let queue = queues.entry(key.clone()).or_insert_with(Vec::new);
+            queue.push((user, Instant::now()));
+            self.prune_lfg_queue(guild, queue);
+            if queue.len() < size as usize {
+                let _ = message.reply(ctx, format!("Queued for **{}** ({}/{}).", tag, queue.len(), size));
+                return;
+            }
+            let players = queue.drain(..size as usize).collect::<Vec<_>>();
+            if queue.is_empty() {
+                queues.remove(&key);
+            }
+            players
+        };
+
+        let owner = players[0].0;
+        let others = players[1..].iter().map(|&(user, _)| user).collect::<Vec<_>>();
+        let name = format!("lfg-{}", tag).chars().take(20).collect::<String>();
+        let layout = PartyLayout::single(&name);
+        let (cat, vc) = match self.create_party(ctx, guild, owner, &name, &layout, &others, Some(u32::from(size))) {
+            Ok((cat, Some(vc))) => (cat, vc),
+            Ok((cat, None)) => unreachable!("LFG party {} was made without a VC", cat),
+            Err(why) => {
+                let _ = message.reply(ctx, why.to_string());
+                // Put everyone back at the front of the queue so they don't lose their place.
+                let mut queues = self.lfg_queue.write();
+                let queue = queues.entry(key).or_insert_with(Vec::new);
+                queue.splice(0..0, players);
+                return;
+            }
+        };
+        self.ratelimit_cache.write().put(owner, Instant::now());
+
+        let mut moved_any = false;
+        for &(player, _) in &players {
+            moved_any |= self.move_member(ctx, guild, player, vc).is_ok();
+        }
+        if !moved_any {
+            self.schedule_cleanup(ctx, cat);
+        }
+        let mentions = players.iter().map(|&(player, _)| player.mention()).collect::<Vec<_>>();
+        let _ = message.channel_id.say(ctx, format!(
+            "Party ready for **{}**: {} (owner: {})",
+            tag, mentions.join(" "), owner.mention()
+        ));
+    }
+
+    // Returns whether the user was in a queue at all.
+    fn leave_lfg_queue(&self, guild: GuildId, user: UserId) -> bool {
+        let mut queues = self.lfg_queue.write();
+        let mut found = false;
+        queues.retain(|(queue_guild, ..), queue| {
+            if *queue_guild == guild {
+                let before = queue.len();
+                queue.retain(|&(queued, _)| queued != user);
+                found |= queue.len() != before;
+            }
+            !queue.is_empty()
+        });
+        found
+    }
+
+    // Drops anyone who has timed out, left voice, or picked up a party of their own since queueing.
+    fn prune_lfg_queue(&self, guild: GuildId, queue: &mut Vec<(UserId, Instant)>) {
+        let voice_channels = self.voice_channels.read();
+        let owner_cache = self.owner_cache.read();
+        queue.retain(|&(user, queued)| {
+            queued.elapsed() < QUEUE_TIMEOUT
+                && voice_channels.contains_key(&user)
+                && !owner_cache.contains_right(&(user, guild, false))
+        });
+    }
+
+    pub fn prune_lfg_queues(&self) {
+        let mut queues = self.lfg_queue.write();
+        for ((guild, ..), queue) in queues.iter_mut() {
+            self.prune_lfg_queue(*guild, queue);
+        }
+        queues.retain(|_, queue| !queue.is_empty());
+    }
+}
@@ -1,29 +1,188 @@
 extern crate crossbeam;
+extern crate ctrlc;
 extern crate fixed_vec_deque;
 extern crate cmd;
 extern crate lru;
 extern crate parking_lot;
 extern crate serenity;
 extern crate bimap;
+extern crate rand;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate prometheus;
+extern crate tiny_http;
+extern crate tracing;
+extern crate tracing_subscriber;
 
-use crossbeam::scope;
+use crossbeam::channel::{bounded, RecvTimeoutError};
 use fixed_vec_deque::FixedVecDeque;
 use lru::LruCache;
 use parking_lot::{RwLock, RwLockWriteGuard};
+use serde::{Deserialize, Serialize};
+use serenity::gateway::ConnectionStage;
 use serenity::http::Http;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
-use std::collections::{BTreeMap, HashMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::Ordering;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::thread::sleep;
+use std::thread;
 use std::time::{Duration, Instant};
 use cmd::Args;
 use bimap::BiBTreeMap;
+use tracing::{debug, error, info, info_span, instrument, warn};
+use tracing_subscriber::EnvFilter;
 
-type CategoryCache = LruCache<ChannelId, (ChannelId, Option<ChannelId>)>;
-type CleanupQueue = FixedVecDeque<[(ChannelId, ChannelId, Option<ChannelId>); 32]>;
+mod adopt;
+mod audit;
+mod cli;
+mod discord;
+mod health;
+mod lfg;
+mod metrics;
+mod panel;
+mod pin;
+mod reconcile;
+mod retry;
+mod shutdown;
+mod split;
+mod state;
+mod threads;
+mod token;
+mod transaction;
+mod voice;
+
+use audit::AuditEvent;
+use cli::{Command, Options};
+use discord::DryRun;
+use health::Health;
+use lfg::LfgEntry;
+use metrics::Metrics;
+use panel::PanelAction;
+use reconcile::{Suspects, RECONCILE_INTERVAL};
+use retry::RetryQueue;
+use shutdown::Shutdown;
+use split::SplitTeams;
+use state::SavedState;
+use threads::ThreadClient;
+use transaction::{CreateError, Transaction};
+use voice::VoiceMembers;
+
+type CategoryCache = BTreeMap<ChannelId, ChannelId>;
+type CleanupQueue = FixedVecDeque<[ChannelId; 32]>;
 
 const PARTY_PREFIX: &str = "+# ";
+const TEXT_ONLY_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+// How long shutdown waits on party creations and then on the cleanup thread.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Parties are keyed by their category, or by their VC for flat thread-mode parties. Either way
+// that's the channel holding the permission overwrites.
+#[derive(Clone, Serialize, Deserialize)]
+struct Party {
+    guild: GuildId,
+    name: String,
+    has_category: bool,
+    voice: Vec<ChannelId>, // The first `squads` are the party's own VCs, everything after is overflow
+    squads: usize,
+    text: Vec<ChannelId>,
+    thread: Option<ChannelId>,
+    limit: Option<u32>,
+    overflow_count: usize, // Only ever goes up, so overflow names don't repeat
+    #[serde(skip)]
+    last_active: Option<Instant>, // Only for text-only parties, since voice_members can't judge them
+    co_owners: BTreeSet<UserId>, // Everyone listed at creation; they can use the panel too
+    #[serde(default)]
+    granted: BTreeSet<UserId>, // Let in since, so joining again needn't grant or audit anything
+    locked: bool, // Whether @everyone is kept out
+    panel: Option<(ChannelId, MessageId)>,
+    #[serde(default)]
+    pinned: bool, // Kept when empty; see pin.rs
+}
+
+// Names of the channels to build when creating a party.
+struct PartyLayout {
+    voice: Vec<String>,
+    text: Vec<String>,
+}
+
+impl PartyLayout {
+    const MAX_VOICE: usize = 10;
+    const MAX_TEXT: usize = 5;
+
+    fn single(name_part: &str) -> PartyLayout {
+        PartyLayout {
+            voice: vec![format!("Party: {}", name_part)],
+            text: vec![format!("party-{}", name_part)],
+        }
+    }
+
+    fn voice_only(self) -> PartyLayout {
+        PartyLayout { text: Vec::new(), ..self }
+    }
+
+    // Text-only parties have no VCs, so they expire after TEXT_ONLY_TIMEOUT without messages.
+    fn text_only(self) -> PartyLayout {
+        PartyLayout { voice: Vec::new(), ..self }
+    }
+
+    // `voice=3` makes numbered channels and `voice=alpha,bravo` makes named ones. Same for `text`,
+    // except you can ask for no text at all.
+    fn from_args(name_part: &str, args: &Args) -> PartyLayout {
+        let single = Self::single(name_part);
+        let voice = Self::channel_names(args.kwargs.get("voice"), &single.voice[0], " ", Self::MAX_VOICE);
+        let text = Self::channel_names(args.kwargs.get("text"), &single.text[0], "-", Self::MAX_TEXT);
+        PartyLayout {
+            voice: if voice.is_empty() { single.voice } else { voice },
+            text,
+        }
+    }
+
+    fn channel_names(spec: Option<&String>, default: &str, sep: &str, max: usize) -> Vec<String> {
+        let spec = match spec {
+            Some(spec) => spec,
+            None => return vec![default.to_string()],
+        };
+        match spec.parse::<usize>() {
+            Ok(1) => vec![default.to_string()],
+            Ok(n) => (1..=n.min(max)).map(|i| format!("{}{}{}", default, sep, i)).collect(),
+            Err(_) => spec
+                .split(',')
+                .map(|name| name.trim().replace('#', "").chars().take(20).collect::<String>())
+                .filter(|name| !name.is_empty())
+                .take(max)
+                .collect(),
+        }
+    }
+}
+
+// Overflow VCs are named "Party: <name> #<n>", which is how they're told apart after a restart.
+fn overflow_number(name: &str) -> Option<usize> {
+    name.rfind(" #").and_then(|i| name[i + 2..].parse().ok())
+}
+
+impl Party {
+    fn headcount(&self, voice: &VoiceMembers) -> u32 {
+        self.voice.iter().map(|&vc| voice.count(self.guild, vc)).sum()
+    }
+
+    fn is_idle(&self, voice: &VoiceMembers) -> bool {
+        if self.pinned {
+            return false;
+        }
+        match self.last_active {
+            Some(last_active) => last_active.elapsed() > TEXT_ONLY_TIMEOUT,
+            None => self.headcount(voice) == 0,
+        }
+    }
+
+    // A voice party with no VCs left, or a party with no channels at all.
+    fn is_broken(&self) -> bool {
+        self.voice.is_empty() && (self.last_active.is_none() || self.text.is_empty())
+    }
+}
 
 static mut USER_ID: UserId = UserId(0);
 
@@ -31,68 +190,669 @@ fn user_id() -> UserId {
     unsafe {USER_ID} // I solemnly swear that I am up to no good
 }
 
+// A party is locked unless @everyone has been let in.
+fn is_locked(guild: GuildId, channel: &GuildChannel) -> bool {
+    !channel.permission_overwrites.iter().any(|o| match o.kind {
+        PermissionOverwriteType::Role(role) => role.0 == guild.0 && o.allow.connect(),
+        _ => false,
+    })
+}
+
+// Works out which categories are parties from a guild's channels, going by PARTY_PREFIX. The
+// state file is what decides which channels are parties now; this is only for taking on parties
+// from before it existed (--import-legacy) and for finding leftovers to purge. Party voice
+// channels are in position order, so the first one is the original.
+fn find_parties<'a>(guild: GuildId, channels: impl Iterator<Item = &'a GuildChannel>) -> Vec<(ChannelId, Party)> {
+    let mut categories = BTreeMap::new();
+    let mut children: BTreeMap<ChannelId, Vec<&GuildChannel>> = BTreeMap::new();
+    let mut flat = Vec::new();
+    let mut categories_locked = BTreeMap::new();
+    for info in channels {
+        match info.kind {
+            ChannelType::Category => {
+                if info.name.starts_with(PARTY_PREFIX) {
+                    categories.insert(info.id, info.name[PARTY_PREFIX.len()..].to_string());
+                    categories_locked.insert(info.id, is_locked(guild, info));
+                }
+            }
+            ChannelType::Text | ChannelType::Voice => {
+                if let Some(cat_id) = info.category_id {
+                    children.entry(cat_id).or_insert_with(Vec::new).push(info);
+                } else if info.kind == ChannelType::Voice && info.name.starts_with(PARTY_PREFIX) {
+                    flat.push(info);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut parties = flat.into_iter()
+        .map(|info| (info.id, Party {
+            guild,
+            name: info.name[PARTY_PREFIX.len()..].to_string(),
+            has_category: false,
+            voice: vec![info.id],
+            squads: 1,
+            text: Vec::new(),
+            thread: None, // There's no finding this again, but it'll auto-archive eventually.
+            limit: info.user_limit.filter(|&limit| limit > 0).map(|limit| limit as u32),
+            overflow_count: 0,
+            last_active: None,
+            co_owners: BTreeSet::new(),
+            granted: BTreeSet::new(),
+            locked: is_locked(guild, info),
+            panel: None,
+            pinned: false,
+        }))
+        .collect::<Vec<_>>();
+    for (cat_id, name) in categories {
+        let mut chans = match children.remove(&cat_id) {
+            Some(chans) => chans,
+            None => continue,
+        };
+        // Squads first, then overflow, each in the order they appear in the client.
+        chans.sort_by_key(|c| (overflow_number(&c.name).is_some(), c.position, c.id));
+        let voice = chans.iter()
+            .filter(|c| c.kind == ChannelType::Voice)
+            .collect::<Vec<_>>();
+        let text = chans.iter()
+            .filter(|c| c.kind == ChannelType::Text)
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        if voice.is_empty() && text.is_empty() {
+            continue;
+        }
+        let limit = voice.first()
+            .and_then(|vc| vc.user_limit)
+            .filter(|&limit| limit > 0)
+            .map(|limit| limit as u32);
+        parties.push((cat_id, Party {
+            guild,
+            name,
+            has_category: true,
+            // A text-only party's clock restarts with the bot; there's nothing better to go on.
+            last_active: if voice.is_empty() { Some(Instant::now()) } else { None },
+            squads: voice.iter().filter(|c| overflow_number(&c.name).is_none()).count().max(1).min(voice.len()),
+            overflow_count: voice.iter().filter_map(|c| overflow_number(&c.name)).max().map_or(0, |n| n.saturating_sub(1)),
+            voice: voice.iter().map(|c| c.id).collect(),
+            text,
+            thread: None,
+            limit,
+            co_owners: BTreeSet::new(),
+            granted: BTreeSet::new(),
+            locked: categories_locked.get(&cat_id).copied().unwrap_or(true),
+            panel: None,
+            pinned: false,
+        }));
+    }
+    parties
+}
+
 struct Bot {
     perms_member: Permissions,
     perms_creator: Permissions,
     cleanup_queue: RwLock<CleanupQueue>,
-    voice_counts: RwLock<BTreeMap<ChannelId, u8>>,
+    voice_members: RwLock<VoiceMembers>,
     voice_channels: RwLock<BTreeMap<UserId, ChannelId>>,
     category_cache: RwLock<CategoryCache>,
-    // category cache is actually vc -> category + txt
-    ignore_cache: RwLock<LruCache<ChannelId, ()>>,
-    owner_cache: RwLock<BiBTreeMap<ChannelId, (UserId, GuildId)>>,
+    // category cache is actually vc -> category, overflow VCs included. It holds every party VC
+    // and is kept current by the channel events, so a miss means it isn't a party's.
+    party_cache: RwLock<BTreeMap<ChannelId, Party>>, // category -> party
+    // category -> (owner, guild, pinned). Pinned parties have their own slot, so owning one
+    // doesn't stop anyone making an ordinary party too.
+    owner_cache: RwLock<BiBTreeMap<ChannelId, (UserId, GuildId, bool)>>,
     // I'm assuming that ChannelId has implied independent domain to GuildId.
     move_role_cache: RwLock<BTreeSet<RoleId>>, // to identify if user has perms to move user
     create_chan_role_cache: RwLock<BTreeSet<RoleId>>, // to identify if user has perms to create channel
     // god forbid should two servers have two roles with identical ids
     guild_owner_cache: RwLock<BTreeMap<GuildId, UserId>>, // Owner always has Administrator perms
     whitelist_role_cache: RwLock<BTreeMap<GuildId, RoleId>>,
+    lfg_channel_cache: RwLock<BTreeMap<GuildId, ChannelId>>, // channel with "+#lfg" in its topic
+    thread_channel_cache: RwLock<BTreeMap<GuildId, (ChannelId, bool)>>, // thread parent, and whether it's flat
+    log_channel_cache: RwLock<BTreeMap<GuildId, ChannelId>>, // channel with "+#log" in its topic
+    threads: ThreadClient,
+    lfg_board: RwLock<BTreeMap<ChannelId, LfgEntry>>, // category -> board entry
+    lfg_queue: RwLock<BTreeMap<(GuildId, String, u8), Vec<(UserId, Instant)>>>, // (guild, tag, size) -> queued users
+    split_cache: RwLock<BTreeMap<ChannelId, SplitTeams>>, // origin vc -> teams split out of it
+    split_orphans: RwLock<BTreeSet<ChannelId>>, // split categories left over from before a restart
+    panel_prompts: RwLock<BTreeMap<(ChannelId, UserId), (ChannelId, PanelAction, Instant)>>, // (channel, user) -> party, pending action
+    metrics: Metrics,
+    health: Health,
+    dry_run: DryRun,
+    import_legacy: bool, // Whether ready takes on PARTY_PREFIX parties the state file doesn't know
+    retries: RetryQueue,
+    shutdown: Shutdown,
+    state_path: PathBuf,
+    pending_state: RwLock<Option<SavedState>>, // loaded at startup, applied once ready has found the parties
+    unswept_guilds: RwLock<BTreeSet<GuildId>>, // unavailable at ready, so their parties wait for guild_create
     ratelimit_cache: RwLock<LruCache<UserId, Instant>>
     // May use (UserId, GuildId) keying instead if people find there is a legitimate need to create
     // multiple parties across guilds within the ratelimit.
 }
 
 impl Bot {
-    fn update_guild_cache(
+    fn may_create_party(&self, guild: GuildId, message: &Message) -> bool {
+        if let Some(&role_id) = self.whitelist_role_cache.read().get(&guild) {
+            let member = message.member.as_ref().unwrap();
+            let chan_role_cache = self.create_chan_role_cache.read();
+            member.roles.iter().any(|r| *r == role_id || chan_role_cache.contains(r))
+                || self.guild_owner_cache.read().get(&guild) == Some(&message.author.id)
+        } else {
+            true
+        }
+    }
+
+    // Creates the category, voice and text channels for a party and caches them. In thread mode
+    // the text channels are swapped for a private thread, and flat thread mode skips the category
+    // and only makes the one VC. Text-only parties ignore thread mode. Everyone in `users` gets
+    // the same permissions as the owner. It's all or nothing: if any step fails, everything made
+    // so far is deleted again. Returns the party's key and its first VC, if it has one.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, http, layout, users), fields(party = tracing::field::Empty))]
+    fn create_party(
         &self,
-        ctx: &Context,
+        http: impl AsRef<Http>,
         guild: GuildId,
-        cache_handle: &mut RwLockWriteGuard<CategoryCache>,
+        owner: UserId,
+        name_part: &str,
+        layout: &PartyLayout,
+        users: &[UserId],
+        limit: Option<u32>,
+    ) -> Result<(ChannelId, Option<ChannelId>), CreateError> {
+        let _in_flight = match self.shutdown.begin() {
+            Some(in_flight) => in_flight,
+            None => return Err(CreateError::Refused("The bot is restarting. Try again in a minute.")),
+        };
+        let text_only = layout.voice.is_empty();
+        if text_only && layout.text.is_empty() {
+            return Err(CreateError::Refused("A party needs at least one channel."));
+        }
+        let thread_parent = if text_only {
+            None
+        } else {
+            self.thread_channel_cache.read().get(&guild).copied()
+        };
+        let flat = thread_parent.map_or(false, |(_, flat)| flat);
+
+        // Set up the initial permissions
+        let members = users
+            .iter()
+            .copied()
+            .chain(std::iter::once(owner))
+            .collect::<Vec<_>>();
+        let initial_user_perms = members
+            .iter()
+            .copied()
+            .chain(std::iter::once(user_id()))
+            .map(|user| PermissionOverwrite {
+                allow: self.perms_creator,
+                deny: Permissions::empty(),
+                kind: PermissionOverwriteType::Member(user),
+            })
+            .chain(std::iter::once(PermissionOverwrite {
+                allow: Permissions::empty(),
+                deny: self.perms_member,
+                kind: PermissionOverwriteType::Role(RoleId(guild.0)),
+            }))
+            .collect::<Vec<_>>();
+
+        let mut tx = Transaction::new(self, guild);
+
+        // Create a category
+        let cat = if flat {
+            None
+        } else {
+            let cat = tx.channel(&http, "create category", |c| {
+                c.name(format!("{}{}", PARTY_PREFIX, name_part))
+                    .permissions(initial_user_perms.clone())
+                    .kind(ChannelType::Category)
+                    .position(200)
+            });
+            match cat {
+                Ok(cat) => Some(cat),
+                Err(stage) => return Err(tx.rollback(&http, stage)),
+            }
+        };
+
+        // Create the channels
+        let vc_names = if flat {
+            // Without a category there's nothing to tie squads together.
+            vec![format!("{}{}", PARTY_PREFIX, name_part)]
+        } else {
+            layout.voice.clone()
+        };
+        let mut voice = Vec::with_capacity(vc_names.len());
+        for vc_name in &vc_names {
+            let vc = tx.channel(&http, "create VC", |c| {
+                c.name(vc_name)
+                    .position(200)
+                    .kind(ChannelType::Voice);
+                match cat {
+                    Some(cat) => c.category(cat),
+                    None => c.permissions(initial_user_perms.clone()),
+                };
+                if let Some(limit) = limit {
+                    c.user_limit(limit);
+                }
+                c
+            });
+            match vc {
+                Ok(vc) => voice.push(vc),
+                Err(stage) => return Err(tx.rollback(&http, stage)),
+            }
+        }
+        let key = cat.unwrap_or_else(|| voice[0]);
+        tracing::Span::current().record("party", &tracing::field::display(key));
+
+        let mut text = Vec::with_capacity(layout.text.len());
+        let mut thread = None;
+        match (thread_parent, cat) {
+            _ if layout.text.is_empty() => {}
+            (Some((parent, _)), _) => {
+                let created = match tx.thread(&http, parent, &format!("party-{}", name_part)) {
+                    Ok(created) => created,
+                    Err(stage) => return Err(tx.rollback(&http, stage)),
+                };
+                for &member in &members {
+                    if let Err(stage) = tx.thread_member(&http, created, member) {
+                        return Err(tx.rollback(&http, stage));
+                    }
+                }
+                thread = Some(created);
+            }
+            (None, Some(cat)) => {
+                for txt_name in &layout.text {
+                    let txt = tx.channel(&http, "create text channel", |c| {
+                        c.name(txt_name)
+                            .position(200)
+                            .kind(ChannelType::Text)
+                            .category(cat)
+                    });
+                    match txt {
+                        Ok(txt) => text.push(txt),
+                        Err(stage) => return Err(tx.rollback(&http, stage)),
+                    }
+                }
+            }
+            (None, None) => unreachable!("Only flat thread-mode parties have no category"),
+        }
+
+        // Everything's made, so it's safe to let the rest of the bot know about it.
+        self.owner_cache.write().insert(key, (owner, guild, false));
+        let mut cat_cache = self.category_cache.write();
+        for &vc in &voice {
+            cat_cache.insert(vc, key);
+        }
+        let first = voice.first().copied();
+        self.party_cache.write().insert(key, Party {
+            guild,
+            name: name_part.to_string(),
+            has_category: cat.is_some(),
+            squads: voice.len(),
+            voice,
+            text,
+            thread,
+            limit,
+            overflow_count: 0,
+            last_active: if text_only { Some(Instant::now()) } else { None },
+            co_owners: users.iter().copied().filter(|&user| user != owner).collect(),
+            granted: BTreeSet::new(),
+            locked: true,
+            panel: None,
+            pinned: false,
+        });
+        drop(cat_cache);
+        self.audit(&http, guild, Some((key, name_part)), AuditEvent::Created { owner, members: users.to_vec() });
+        self.post_panel(&http, key);
+        Ok((key, first))
+    }
+
+    // Lets someone into a party: permissions on the category (or flat VC) and the thread, if any.
+    // `via` and `by` are only for the audit log.
+    fn grant_party_access(
+        &self,
+        http: impl AsRef<Http>,
+        key: ChannelId,
+        user: UserId,
+        via: &'static str,
+        by: Option<UserId>,
+    ) -> serenity::Result<()> {
+        let res = self.set_permission(
+            &http,
+            key,
+            &PermissionOverwrite {
+                allow: self.perms_member,
+                deny: Permissions::empty(),
+                kind: PermissionOverwriteType::Member(user),
+            },
+        );
+        if let Err(ref e) = res {
+            self.audit_party(&http, key, AuditEvent::Failed { stage: "grant access", error: format!("{:?}", e) });
+        }
+        res?;
+        let thread = match self.party_cache.write().get_mut(&key) {
+            Some(party) => {
+                party.granted.insert(user);
+                party.thread
+            }
+            None => None,
+        };
+        if let Some(thread) = thread {
+            self.add_thread_member(thread, user);
+        }
+        self.audit_party(&http, key, AuditEvent::Granted { user, via, by });
+        Ok(())
+    }
+
+    // Schedule the party to be checked again after a couple of minutes and to be deleted
+    // if it is not in use.
+    fn schedule_cleanup(&self, http: impl AsRef<Http>, cat: ChannelId) {
+        let mut queue = self.cleanup_queue.write();
+        if queue.is_full() {
+            let old = *queue.front().unwrap();
+            self.metrics.cleanup_evictions.inc();
+            // We're about to write over the last so we should check it
+            // If it's empty, tidy it
+            if self.party_is_idle(old, &self.voice_members.read()) {
+                self.teardown_party(&http, old, "Nobody joined before it was pushed out of the cleanup queue");
+            }
+            // If it's not empty, it'll get cleaned later.
+        }
+        *queue.push_back() = cat;
+    }
+
+    fn party_is_idle(&self, cat: ChannelId, voice: &VoiceMembers) -> bool {
+        self.party_cache.read().get(&cat).map_or(true, |party| party.is_idle(voice))
+    }
+
+    // Text-only parties have no voice_members to go on, so every message keeps them alive.
+    fn touch_text_party(&self, channel: ChannelId) {
+        let key = self.party_cache.read().iter()
+            .find(|(_, party)| party.last_active.is_some() && party.text.contains(&channel))
+            .map(|(&key, _)| key);
+        if let Some(key) = key {
+            if let Some(party) = self.party_cache.write().get_mut(&key) {
+                party.last_active = Some(Instant::now());
+            }
+        }
+    }
+
+    fn expire_text_parties(&self, http: impl AsRef<Http>) {
+        let expired = self.party_cache.read().iter()
+            .filter(|(_, party)| party.last_active.is_some() && party.is_idle(&VoiceMembers::default()))
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for key in expired {
+            info!(party = %key, "Text-only party expired; cleaning up");
+            self.teardown_party(&http, key, "No messages for too long");
+        }
+    }
+
+    // Deletes every piece of a party and forgets about it. The VCs are left in category_cache
+    // since callers are usually holding that lock; the channel_delete events clear them out.
+    // `reason` goes in the audit log.
+    #[instrument(skip(self, http, cat), fields(party = %cat))]
+    fn teardown_party(&self, http: impl AsRef<Http>, cat: ChannelId, reason: &str) -> Option<Party> {
+        let party = self.party_cache.write().remove(&cat)?;
+        let mut failed = Vec::new();
+        for &chan in party.voice.iter().chain(&party.text) {
+            if let Err(e) = self.delete_channel(&http, chan) {
+                failed.push(format!("{}: {:?}", chan, e));
+            }
+        }
+        if let Some(thread) = party.thread {
+            if !self.archive_thread(thread) {
+                failed.push(format!("{}: couldn't archive the thread", thread));
+            }
+        }
+        if party.has_category {
+            if let Err(e) = self.delete_channel(&http, cat) {
+                failed.push(format!("{}: {:?}", cat, e));
+            }
+        }
+        self.owner_cache.write().remove_by_left(&cat);
+        self.remove_lfg_entry(&http, cat);
+        let name = Some((cat, party.name.as_str()));
+        if !failed.is_empty() {
+            self.audit(&http, party.guild, name, AuditEvent::Failed { stage: "delete channels", error: failed.join("\n") });
+        }
+        self.audit(&http, party.guild, name, AuditEvent::Cleanup { reason: reason.to_string() });
+        Some(party)
+    }
+
+    // One of a party's VCs has emptied. Overflow channels go as soon as they're empty, but the
+    // party only goes once all of them are.
+    fn voice_channel_emptied(
+        &self,
+        http: impl AsRef<Http>,
+        cat: ChannelId,
+        vc: ChannelId,
+        voice: &VoiceMembers,
+        cache: &mut CategoryCache,
     ) {
-        let channel_info = guild.channels(&ctx);
-        if channel_info.is_err() {
-            // This is a disaster!
-            eprintln!("Failed to get channels for {}; {:?}", guild.0, channel_info);
+        let span = info_span!("voice_channel_emptied", party = %cat, channel = %vc);
+        let _enter = span.enter();
+        if self.party_is_idle(cat, voice) {
+            if let Some(party) = self.teardown_party(&http, cat, "Everyone left") {
+                for vc in &party.voice {
+                    cache.remove(vc);
+                }
+            }
             return;
         }
-        let channel_info = channel_info.unwrap();
-        let mut category_map = BTreeMap::new();
-        let mut category_list = Vec::new();
-        for (id, info) in channel_info {
-            match info.kind {
-                ChannelType::Category => {
-                    if info.name.starts_with(PARTY_PREFIX) {
-                        category_list.push(id);
-                    }
+        let mut party_cache = self.party_cache.write();
+        if let Some(party) = party_cache.get_mut(&cat) {
+            if !party.voice[..party.squads].contains(&vc) {
+                party.voice.retain(|&v| v != vc);
+                let _ = self.delete_channel(&http, vc);
+                cache.remove(&vc);
+            }
+        }
+    }
+
+    // If every VC in a limited party is full, open another one.
+    fn overflow_if_full(
+        &self,
+        http: impl AsRef<Http>,
+        cat: ChannelId,
+        voice: &VoiceMembers,
+        cache: &mut CategoryCache,
+    ) {
+        let mut party_cache = self.party_cache.write();
+        let party = match party_cache.get_mut(&cat) {
+            Some(party) => party,
+            None => return,
+        };
+        let limit = match party.limit {
+            Some(limit) if party.has_category => limit,
+            // Flat parties have nowhere to put an overflow channel.
+            _ => return,
+        };
+        if party.voice.iter().any(|&vc| voice.count(party.guild, vc) < limit) {
+            return;
+        }
+        party.overflow_count += 1;
+        let number = party.overflow_count + 1;
+        let vc = self.create_channel(&http, party.guild, |c| {
+            c.name(format!("Party: {} #{}", party.name, number))
+                .position(200)
+                .kind(ChannelType::Voice)
+                .category(cat)
+                .user_limit(limit)
+        });
+        match vc {
+            Ok(vc) => {
+                party.voice.push(vc);
+                cache.insert(vc, cat);
+            }
+            Err(e) => {
+                warn!(party = %cat, error = ?e, "Failed to create overflow VC");
+                self.audit(&http, party.guild, Some((cat, &party.name)), AuditEvent::Failed {
+                    stage: "create overflow VC",
+                    error: format!("{:?}", e),
+                });
+            }
+        }
+    }
+
+    // Keeps category_cache and the parties' channel lists in step with a channel that's been
+    // moved. Only channels the bot made are part of a party, so dragging a channel into a
+    // party's category doesn't make it one, but dragging one out takes it away. Returns the
+    // party it left, if it left one.
+    fn index_channel(&self, channel: &GuildChannel) -> Option<ChannelId> {
+        let mut cache = self.category_cache.write();
+        let mut party_cache = self.party_cache.write();
+        let key = Self::party_of(&cache, &party_cache, channel.id)?;
+        let party = party_cache.get(&key)?;
+        // A flat party's VC is the party itself, so it has nowhere to leave.
+        if !party.has_category || channel.category_id == Some(key) {
+            return None;
+        }
+        debug!(party = %key, channel = %channel.id, "Channel left party");
+        Self::remove_party_channel(&mut cache, &mut party_cache, key, channel.id);
+        Some(key)
+    }
+
+    // Text channels aren't in category_cache, so they take a walk through the parties.
+    fn party_of(cache: &CategoryCache, party_cache: &BTreeMap<ChannelId, Party>, chan: ChannelId) -> Option<ChannelId> {
+        cache.get(&chan).copied().or_else(|| {
+            party_cache.iter().find(|(_, party)| party.text.contains(&chan)).map(|(&key, _)| key)
+        })
+    }
+
+    fn remove_party_channel(cache: &mut CategoryCache, party_cache: &mut BTreeMap<ChannelId, Party>, key: ChannelId, chan: ChannelId) {
+        cache.remove(&chan);
+        if let Some(party) = party_cache.get_mut(&key) {
+            if let Some(i) = party.voice.iter().position(|&vc| vc == chan) {
+                party.voice.remove(i);
+                if i < party.squads {
+                    party.squads -= 1;
                 }
-                ChannelType::Text | ChannelType::Voice => {
-                    if let Some(cat_id) = info.category_id {
-                        let mut entry = category_map.entry(cat_id).or_insert((ChannelId(0), None));
-                        if info.kind == ChannelType::Text {
-                            entry.1 = Some(id);
-                        } else {
-                            entry.0 = id;
-                        }
+            }
+            party.text.retain(|&txt| txt != chan);
+        }
+    }
+
+    // Returns the party the channel was part of, if any.
+    fn unindex_channel(&self, channel: &GuildChannel) -> Option<ChannelId> {
+        let mut cache = self.category_cache.write();
+        let mut party_cache = self.party_cache.write();
+        let key = Self::party_of(&cache, &party_cache, channel.id)?;
+        Self::remove_party_channel(&mut cache, &mut party_cache, key, channel.id);
+        Some(key)
+    }
+
+    // Someone deleted one of a party's channels or dragged it out of the category, and it's
+    // already been taken off the party. What's left carries on if it's still a party; a voice
+    // party with no VCs or a party with no channels at all is torn down.
+    fn party_channel_lost(&self, http: impl AsRef<Http>, key: ChannelId, name: &str, how: &str) {
+        let found = self.party_cache.read().get(&key).map(|party| (party.guild, party.name.clone(), party.is_broken()));
+        let (guild, party_name, broken) = match found {
+            Some(found) => found,
+            None => return,
+        };
+        let change = format!("{} was {}", name, how);
+        info!(party = %key, change = %change, broken, "Party channel changed outside the bot");
+        if broken {
+            self.teardown_party(&http, key, &format!("{}, leaving nothing usable", change));
+        } else {
+            self.audit(&http, guild, Some((key, &party_name)), AuditEvent::Changed { change });
+        }
+    }
+
+    // A party's category (or flat VC) was renamed by someone else. Keeping PARTY_PREFIX just
+    // renames the party; dropping it means they want the channels for something else, so the
+    // bot lets go of them.
+    fn party_renamed(&self, http: impl AsRef<Http>, key: ChannelId, name: &str) {
+        let renamed = {
+            let mut party_cache = self.party_cache.write();
+            let party = match party_cache.get_mut(&key) {
+                Some(party) => party,
+                None => return,
+            };
+            match name.strip_prefix(PARTY_PREFIX) {
+                // The panel's renames come back round as events too.
+                Some(new_name) if new_name == party.name => return,
+                Some(new_name) => {
+                    party.name = new_name.to_string();
+                    true
+                }
+                None => false,
+            }
+        };
+        if renamed {
+            info!(party = %key, name, "Party renamed outside the bot");
+            self.audit_party(&http, key, AuditEvent::Changed { change: format!("Renamed to {}", name) });
+        } else {
+            self.release_party(&http, key, &format!("Renamed to {}, so it's no longer a party", name));
+        }
+    }
+
+    // Forgets about a party without deleting anything, leaving the channels to whoever has them.
+    fn release_party(&self, http: impl AsRef<Http>, key: ChannelId, reason: &str) -> Option<Party> {
+        let mut cache = self.category_cache.write();
+        let party = self.party_cache.write().remove(&key)?;
+        for vc in &party.voice {
+            cache.remove(vc);
+        }
+        drop(cache);
+        self.owner_cache.write().remove_by_left(&key);
+        self.remove_lfg_entry(&http, key);
+        info!(party = %key, reason, "Released party");
+        self.audit(&http, party.guild, Some((key, &party.name)), AuditEvent::Released { reason: reason.to_string() });
+        Some(party)
+    }
+
+    // Tears down the parties in `guilds` that came back empty or with nothing usable left.
+    fn startup_sweep(&self, http: impl AsRef<Http>, guilds: &BTreeSet<GuildId>, voice: &VoiceMembers, cache: &mut CategoryCache) {
+        let parties = self.party_cache.read().iter()
+            .filter(|(_, party)| guilds.contains(&party.guild))
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for cat in parties {
+            let (v, idle, broken) = self.party_cache.read().get(&cat)
+                .map_or((0, true, true), |party| (party.headcount(voice), party.is_idle(voice), party.is_broken()));
+            debug!(party = %cat, headcount = v, "Found party at startup");
+            if idle || broken {
+                let reason = if broken {
+                    "Its channels were deleted while the bot was down"
+                } else {
+                    "Empty when the bot started"
+                };
+                // Delete it
+                if let Some(party) = self.teardown_party(&http, cat, reason) {
+                    for vc in &party.voice {
+                        cache.remove(vc);
                     }
                 }
-                _ => {}
             }
         }
-        for cat_id in category_list {
-            if let Some((vc_id, txt_id)) = category_map.remove(&cat_id) {
-                cache_handle.put(vc_id, (cat_id, txt_id));
+    }
+
+    // The first guild_create for a guild ready didn't see: now its channels and voice states
+    // are known, its parties get what the others got at ready.
+    fn catch_up_guild(&self, http: impl AsRef<Http>, guild: &Guild) {
+        let existing = guild.channels.keys().copied().collect::<BTreeSet<_>>();
+        let voice = self.voice_members.read();
+        let mut cache = self.category_cache.write();
+        for (&key, party) in self.party_cache.write().iter_mut().filter(|(_, party)| party.guild == guild.id) {
+            for vc in &party.voice {
+                cache.remove(vc);
+            }
+            state::forget_deleted(key, party, &existing);
+            for &vc in &party.voice {
+                cache.insert(vc, key);
             }
         }
+        let mut guilds = BTreeSet::new();
+        guilds.insert(guild.id);
+        self.startup_sweep(&http, &guilds, &voice, &mut cache);
+    }
+
+    // Guild features are configured by markers in channel topics.
+    fn update_marked_channel(&self, channel: &GuildChannel) {
+        self.update_lfg_channel(channel);
+        self.update_thread_channel(channel);
+        self.update_log_channel(channel);
     }
 
     fn update_role(&self, role: &Role) {
@@ -120,34 +880,52 @@ impl EventHandler for Bot {
             return;
         }
         let guild = message.guild_id.unwrap();
+        let span = info_span!("message", guild = %guild, channel = %message.channel_id, user = %message.author.id);
+        let _enter = span.enter();
+        self.touch_text_party(message.channel_id);
+        if self.handle_panel_prompt(&ctx, &message) {
+            return;
+        }
         if message.content.starts_with("/party") {
+            let mut rest = &message.content[6..];
+            let variant = rest.split_whitespace().next();
+            match variant {
+                Some("split") => return self.split_command(&ctx, &message, guild),
+                Some("regroup") => return self.regroup_command(&ctx, &message, guild),
+                Some("adopt") => return self.adopt_command(&ctx, &message, guild),
+                Some("release") => return self.release_command(&ctx, &message, guild),
+                Some("pin") => return self.pin_command(&ctx, &message, guild, true),
+                Some("unpin") => return self.pin_command(&ctx, &message, guild, false),
+                Some("voice-only") | Some("text-only") => {
+                    rest = rest.trim_start().splitn(2, char::is_whitespace).nth(1).unwrap_or("");
+                }
+                _ => {}
+            }
             let now = Instant::now();
             let since = self.ratelimit_cache.read().peek(&message.author.id)
                 .map(|&last| now.duration_since(last))
                 .unwrap_or_else(|| Duration::from_secs(301));
             if since < Duration::from_secs(20) {
                 // This is both for the bot's sake and to prevent nuisance abuse of the bot
+                self.metrics.ratelimit_rejections.with_label_values(&["silent"]).inc();
                 return;
-            } else if self.owner_cache.read().contains_right(&(message.author.id, guild)) {
+            } else if self.owner_cache.read().contains_right(&(message.author.id, guild, false)) {
                 let _ = message.reply(&ctx, "You already have a party! Disband it first.");
+                self.metrics.ratelimit_rejections.with_label_values(&["already owner"]).inc();
                 self.ratelimit_cache.write().put(message.author.id, now);
                 return;
             } else if since < Duration::from_secs(300) {
+                self.metrics.ratelimit_rejections.with_label_values(&["cooldown"]).inc();
                 let _ = message.reply(&ctx, format!("You're making parties too fast! Wait another {} seconds", 300-since.as_secs()));
                 return;
             }
             self.ratelimit_cache.write().put(message.author.id, Instant::now());
-            if let Some(&role_id) = self.whitelist_role_cache.read().get(&guild) {
-                let member = message.member.as_ref().unwrap();
-                let chan_role_cache = self.create_chan_role_cache.read();
-                if !(member.roles.iter().any(|r| *r == role_id || chan_role_cache.contains(r))
-                    || self.guild_owner_cache.read().get(&guild) == Some(&message.author.id))  {
-                    // This needs rate-limiting too or people will be extremely funny.
-                    let _ = message.reply(&ctx, "You do not have permission to use this command");
-                    return;
-                }
+            if !self.may_create_party(guild, &message) {
+                // This needs rate-limiting too or people will be extremely funny.
+                let _ = message.reply(&ctx, "You do not have permission to use this command");
+                return;
             }
-            let args = Args::parse(&message.content[6..]);
+            let args = Args::parse(rest);
             if args.is_err() {
                 let _ = message.reply(ctx, "Failed to parse command!");
                 return;
@@ -170,116 +948,63 @@ impl EventHandler for Bot {
             } else {
                 message.id.to_string()
             };
-            // Set up the initial permissions
+            // A tag advertises the party on the guild's LFG board, if it has one.
+            let tag = args.kwargs.get("tag").map(|tag| tag.chars().take(32).collect::<String>());
+            let limit = args.kwargs.get("limit")
+                .and_then(|limit| limit.parse::<u32>().ok())
+                .filter(|&limit| limit > 0 && limit < 100); // Discord caps voice channels at 99
             let listed_users = args
                 .args
                 .iter()
-                .filter_map(|arg| arg.parse::<UserId>().ok());
-            let users = listed_users.clone()
-                .chain(std::iter::once(message.author.id))
-                .chain(std::iter::once(user_id()));
-            let initial_user_perms = users
-                .clone()
-                .map(|user| PermissionOverwrite {
-                    allow: self.perms_creator,
-                    deny: Permissions::empty(),
-                    kind: PermissionOverwriteType::Member(user),
-                })
-                .chain(std::iter::once(PermissionOverwrite {
-                    allow: Permissions::empty(),
-                    deny: self.perms_member,
-                    kind: PermissionOverwriteType::Role(RoleId(guild.0)),
-                }));
-
-            // Create a category
-            let cat = guild.create_channel(&ctx, |c| {
-                c.name(format!("{}{}", PARTY_PREFIX, &name_part))
-                    .permissions(initial_user_perms.clone())
-                    .kind(ChannelType::Category)
-                    .position(200)
-            });
-            let cat = if let Ok(cat) = cat {cat} else {
-                let _ = message.reply(&ctx, "Failed to create category.");
-                return;
+                .filter_map(|arg| arg.parse::<UserId>().ok())
+                .collect::<Vec<_>>();
+            let layout = match variant {
+                Some("voice-only") => PartyLayout::from_args(&name_part, &args).voice_only(),
+                Some("text-only") => PartyLayout::from_args(&name_part, &args).text_only(),
+                _ => PartyLayout::from_args(&name_part, &args),
+            };
+            let (cat, vc) = match self.create_party(&ctx, guild, message.author.id, &name_part, &layout, &listed_users, limit) {
+                Ok(chans) => chans,
+                Err(why) => {
+                    let _ = message.reply(&ctx, why.to_string());
+                    return;
+                }
             };
 
-            // Create the channels
-            let vc = guild.create_channel(&ctx, |c| {
-                c.name(format!("Party: {}", name_part))
-                    .position(200)
-                    //.permissions(initial_user_perms.clone())
-                    .kind(ChannelType::Voice)
-                    .category(cat.id)
-            });
-            let vc = if let Ok(vc) = vc {vc} else {
-                let _ = message.reply(&ctx, "Failed to create VC.");
-                let res = cat.delete(&ctx);
-                if res.is_err() {
-                    let _ = message.reply(&ctx, "Also failed to delete the category. Disaster.");
+            if let Some(tag) = tag {
+                self.post_lfg_entry(&ctx, guild, cat, name_part, tag);
+            }
+
+            let vc = match vc {
+                Some(vc) => vc,
+                None => {
+                    let _ = message.reply(&ctx, format!(
+                        "Text-only party created. It'll be cleaned up after {} minutes without any messages.",
+                        TEXT_ONLY_TIMEOUT.as_secs() / 60
+                    ));
+                    return;
                 }
-                return;
             };
-            self.owner_cache.write().insert(vc.id, (message.author.id, guild));
-
-            let txt = guild
-                .create_channel(&ctx, |c| {
-                    c.name(format!("party-{}", name_part))
-                        .position(200)
-                        .kind(ChannelType::Text)
-                        //.permissions(initial_user_perms)
-                        .category(cat.id)
-                })
-                .ok();
-            if txt.is_none() {
-                let _ = message.reply(&ctx, "Failed to create text channel. Voice only.");
-            }
-
-            // Add that shit to the cache.
-            let mut cat_cache = self.category_cache.write();
-            cat_cache.put(vc.id, (cat.id, txt.as_ref().map(|c| c.id)));
 
             // Now, if the user is in voice, we should move them.
-            let moved = guild.move_member(&ctx, message.author.id, vc.id);
+            let moved = self.move_member(&ctx, guild, message.author.id, vc);
             // If we can't move them, schedule the channel to be checked again
             // after a couple of minutes and to be deleted if it is not in use.
             if moved.is_err() {
-                let mut queue = self.cleanup_queue.write();
-                if queue.is_full() {
-                    let old = queue.front().unwrap();
-                    // We're about to write over the last so we should check it
-                    // If it's empty, tidy it
-                    let count = self.voice_counts.read().get(&old.0).copied().unwrap_or(0);
-                    if count == 0 {
-                        let _ = old.1.delete(&ctx);
-                        if let Some(ref txt) = old.2 {
-                            let _ = txt.delete(&ctx);
-                        }
-                        let _ = old.1.delete(&ctx);
-                        // This should work because the last use of the read lock
-                        // was above. NLL or something good like that. If not I
-                        // can manually drop(map) anyway.
-                        self.voice_counts.write().remove(&old.0);
-                        // Also clean the owner cache for the channel
-                        self.owner_cache.write().remove_by_left(&old.1);
-                    }
-                    // If it's not empty, it'll get cleaned later.
-                }
-                *queue.push_back() = (cat.id, vc.id, txt.map(|c| c.id));
+                self.schedule_cleanup(&ctx, cat);
             } else {
                 // If we moved them just fine, check if we should move everyone else they've added
-                // The users iterator includes the owner but this should be a fine no-op.
-                let role_cache = self.move_role_cache.read();
-                if message.member.unwrap().roles.iter().any(|r| role_cache.contains(r))
-                    || self.guild_owner_cache.read().get(&guild) == Some(&message.author.id)
-                {
-                    for user in listed_users.clone() {
+                if self.may_move_members(guild, &message) {
+                    for &user in &listed_users {
                         // Dump the result, we don't actually care if they succeeded.
-                        let _ = guild.move_member(&ctx, user, vc.id);
+                        let _ = self.move_member(&ctx, guild, user, vc);
                     }
                 }
 
             };
 
+        } else if message.content.starts_with("/lfg") {
+            self.lfg_command(&ctx, &message, guild);
         }
     }
 
@@ -288,62 +1013,55 @@ impl EventHandler for Bot {
             return;
         }
         let guild = guild.expect("what the fuck");
+        let span = info_span!("voice_state_update", guild = %guild, user = %voice.user_id, channel = ?voice.channel_id);
+        let _enter = span.enter();
         let mut member_map = self.voice_channels.write();
-        let mut count_map = self.voice_counts.write();
+        if member_map.get(&voice.user_id) == voice.channel_id.as_ref() {
+            // Mute, deafen, stream and so on. They haven't gone anywhere.
+            return;
+        }
+        let mut voice_members = self.voice_members.write();
         if let Some(old_channel) = member_map.remove(&voice.user_id) {
-            if let Some(old_count) = count_map.get_mut(&old_channel) {
-                *old_count -= 1;
-                if *old_count == 0 {
-                    count_map.remove(&old_channel);
-                    // Channel is empty; clean it up.
-                    // Check for it in the category cache
-                    let mut cache = self.category_cache.write();
-                    if cache.peek(&old_channel).is_none() {
-                        // We need to get the channels which match, so we should
-                        // fetch all channels and update the cache for a server.
-                        self.update_guild_cache(&ctx, guild, &mut cache);
-                    }
-                    if let Some(chans) = cache.pop(&old_channel) {
-                        let _ = old_channel.delete(&ctx);
-                        if let Some(chan) = chans.1 {
-                            let _ = chan.delete(&ctx);
-                        }
-                        let _ = chans.0.delete(&ctx);
-                        self.owner_cache.write().remove_by_left(&old_channel);
-                    } else {
-                        eprintln!("Failed to get channels after cache reload for {:?}", guild);
-                        // This could be an ignored channel: i.e. it's not managed by the bot
-                        // If this keeps happening, look at updating the ignore
-                        // cache at the same time. If it keeps happening then,
-                        // look at dynamically scaling the cache when it happens.
-
-                        // This is a hack.
-                        println!("Ignoring {:?}", old_channel);
-                        let mut ignore_cache = self.ignore_cache.write();
-                        ignore_cache.put(old_channel, ());
-                    }
-                }
+            let emptied = voice_members.leave(guild, old_channel, voice.user_id);
+            let mut cache = self.category_cache.write();
+            let cat = cache.get(&old_channel).copied();
+            self.metrics.cache_lookup("category_cache", cat.is_some());
+            if let Some(cat) = cat {
+                self.refresh_lfg_entry(&ctx, cat, &voice_members);
+                self.refresh_panel(&ctx, cat, &voice_members);
+            }
+            if !emptied {
+                // Still people in there.
+            } else if self.split_team_emptied(&ctx, old_channel, &voice_members) {
+                // Teams aren't parties, so there's nothing else to tidy.
             } else {
-                // We didn't actually have information on the channel.
-                // It's game over really. There's nothing to be done here.
-                eprintln!(
-                    "A user disconnected from an uncached channel in {} ({})",
-                    guild, old_channel
-                );
+                // Channel is empty; clean it up.
+                // If it isn't a party channel, there's nothing to clean up.
+                if let Some(cat) = cat {
+                    self.voice_channel_emptied(&ctx, cat, old_channel, &voice_members, &mut cache);
+                }
             }
         }
         if let Some(chan) = voice.channel_id {
-            let mut ignore_cache = self.ignore_cache.write();
-            if ignore_cache.get(&chan).is_some() {
-                // It's one of the ones we're already ignoring.
-                return;
-            }
             // Moved to a new channel
             member_map.insert(voice.user_id, chan);
-            *count_map.entry(chan).or_insert(0) += 1;
+            voice_members.join(guild, chan, voice.user_id);
+        }
+        self.metrics.voice_users.with_label_values(&[&guild.to_string()]).set(voice_members.guild_total(guild) as i64);
+        if let Some(chan) = voice.channel_id {
+            let mut cat_cache = self.category_cache.write();
+            let cat_id = cat_cache.get(&chan).copied();
+            self.metrics.cache_lookup("category_cache", cat_id.is_some());
+            let cat_id = match cat_id {
+                Some(cat_id) => cat_id,
+                None => return,
+            };
+            self.refresh_lfg_entry(&ctx, cat_id, &voice_members);
+            self.refresh_panel(&ctx, cat_id, &voice_members);
+            self.overflow_if_full(&ctx, cat_id, &voice_members, &mut cat_cache);
 
             let owner_cache = self.owner_cache.read();
-            if owner_cache.get_by_left(&chan) == Some(&(voice.user_id, guild)) { // .contains does not update LRU
+            if owner_cache.get_by_left(&cat_id).map_or(false, |&(owner, ..)| owner == voice.user_id) { // .contains does not update LRU
                 // The user is an owner of this channel. They already have perms.
                 // Also I updated the way channel owners work so this is now slightly broken and
                 // doesn't maintain the permissions for the initial users. I need to either fix that
@@ -352,41 +1070,44 @@ impl EventHandler for Bot {
                 return;
             }
 
-            // If we're tracking it, we should make sure they have permissions.
-            let mut cat_cache = self.category_cache.write();
-            if let Some((cat_id, _)) = cat_cache.get(&chan) {
-                let res = cat_id.create_permission(
-                    &ctx,
-                    &PermissionOverwrite {
-                        allow: self.perms_member,
-                        deny: Permissions::empty(),
-                        kind: PermissionOverwriteType::Member(voice.user_id),
-                    },
-                );
-                if res.is_err() {
-                    eprintln!("Failed to set category perms; {:?}", res);
-                }
+            // If we're tracking it, we should make sure they have permissions. Anyone listed at
+            // creation or let in before already has an overwrite, so rejoining isn't news.
+            drop(owner_cache);
+            let has_access = self.party_cache.read().get(&cat_id)
+                .map_or(false, |party| party.co_owners.contains(&voice.user_id) || party.granted.contains(&voice.user_id));
+            if has_access {
+                debug!(party = %cat_id, "Rejoined a party they already have access to");
+                return;
+            }
+            let res = self.grant_party_access(&ctx, cat_id, voice.user_id, "joined voice", None);
+            if res.is_err() {
+                warn!(party = %cat_id, error = ?res, "Failed to set category perms");
             }
         }
     }
 
     fn ready(&self, ctx: Context, ready: Ready) {
+        let span = info_span!("ready", user = %ready.user.id);
+        let _enter = span.enter();
         let guilds = ready.guilds.iter().filter_map(|status| 
             if let GuildStatus::OnlineGuild(guild) = status {Some(guild)} else {None}
         );
         let mut category_cache = self.category_cache.write();
         let mut voice_map = self.voice_channels.write(); // User channel tracker (for decrement)
-        let mut counts = self.voice_counts.write(); // User channel counts
+        let mut voice_members = self.voice_members.write(); // Who's in each channel
         let mut move_role_cache = self.move_role_cache.write();
         let mut create_chan_role_cache = self.create_chan_role_cache.write();
         let mut guild_owner_cache = self.guild_owner_cache.write();
         let mut whitelist_cache = self.whitelist_role_cache.write();
+        let mut seen = BTreeSet::new();
+        let mut existing = BTreeSet::new();
+        let mut legacy = Vec::new();
         for guild in guilds {
             // Update the role caches
             for (.., role) in &guild.roles {
                 Self::update_role_raw(&mut move_role_cache, &mut create_chan_role_cache, role);
                 if role.name.starts_with("+#") && whitelist_cache.insert(guild.id, role.id).is_some() {
-                    eprintln!("{:?} has multiple '+#' roles", guild.id)
+                    warn!(guild = %guild.id, "Guild has multiple '+#' roles")
                     // In the event that they have multiple I'll need to sort out something smarter.
                     // I'm leaving this here has acknowledgement of that fact, giving me a way to
                     // defer implementing a smarter solution to a time that it is required.
@@ -396,67 +1117,39 @@ impl EventHandler for Bot {
             // Update guild-owner cache
             guild_owner_cache.insert(guild.id, guild.owner_id);
 
-            // This code is copy-pasted
-            // Please refactor.
-            let mut category_map = HashMap::new();
-            let mut category_list = Vec::new();
-            for (&id, info) in &guild.channels {
-                let info = info.read();
-                match info.kind {
-                    ChannelType::Category => {
-                        if info.name.starts_with(PARTY_PREFIX) {
-                            category_list.push(id);
-                        }
-                    }
-                    ChannelType::Text | ChannelType::Voice => {
-                        if let Some(cat_id) = info.category_id {
-                            let mut entry = category_map.entry(cat_id).or_insert((ChannelId(0), None));
-                            if info.kind == ChannelType::Text {
-                                entry.1 = Some(id);
-                            } else {
-                                entry.0 = id;
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+            let channels = guild.channels.values().map(|c| c.read()).collect::<Vec<_>>();
+            for info in &channels {
+                self.update_marked_channel(info);
+                existing.insert(info.id);
             }
-            for cat_id in category_list {
-                if let Some((vc_id, txt_id)) = category_map.remove(&cat_id) {
-                    category_cache.put(vc_id, (cat_id, txt_id));
-                }
-            }
-            
-            // Populate the ignore cache with every channel not matched to a party
-            let mut ignore = self.ignore_cache.write();
-            for (_, (vc_id, _)) in category_map.drain() { 
-                ignore.put(vc_id, ()); // I really need some kind of LRU set
+            seen.insert(guild.id);
+            if self.import_legacy {
+                legacy.extend(find_parties(guild.id, channels.iter().map(|c| &**c)));
             }
 
+            voice_members.reset_guild(guild.id);
             for (&user, voice) in &guild.voice_states {
-                *counts.entry(voice.channel_id.expect("User voice not in channel at ready")).or_insert(0) += 1;
-                voice_map.insert(user, voice.channel_id.unwrap());
-            }
-        }
-
-        for (&chan, &v) in counts.clone().iter() {
-            println!("{}, {}", chan, v);
-            let cat_info = category_cache.peek(&chan);
-            if let Some(info) = cat_info {
-                if v == 0 {
-                    // Delete it
-                    // category_cache is an LRU cache so removal will happen automatically
-                    counts.remove(&chan);
-                    let _ = chan.delete(&ctx);
-                    if let Some(txt) = info.1 {
-                        let _ = txt.delete(&ctx);
-                    }
-                    let _ = info.0.delete(&ctx);
-                }
+                let chan = voice.channel_id.expect("User voice not in channel at ready");
+                voice_members.join(guild.id, chan, user);
+                voice_map.insert(user, chan);
             }
+            self.metrics.voice_users.with_label_values(&[&guild.id.to_string()]).set(voice_members.guild_total(guild.id) as i64);
         }
 
+        self.restore_state(&seen, &existing, legacy, &mut category_cache);
+
+        // Nobody's voice states are known for the guilds ready didn't see, so their parties
+        // would all look empty. They're swept when their guild_create turns up instead.
+        let unseen = self.party_cache.read().values()
+            .map(|party| party.guild)
+            .filter(|guild| !seen.contains(guild))
+            .collect::<BTreeSet<_>>();
+        self.unswept_guilds.write().extend(unseen);
+        self.startup_sweep(&ctx, &seen, &voice_members, &mut category_cache);
+
         unsafe {USER_ID = ready.user.id};
+        self.health.gateway_connected.store(true, Ordering::SeqCst);
+        self.health.ready_processed.store(true, Ordering::SeqCst);
         //ctx.set_activity(/*activity*/);
         // Serenity doesn't support a custom activity
         // Despite this, it has the custom activity type
@@ -490,13 +1183,101 @@ impl EventHandler for Bot {
         }
     }
 
-    fn guild_create(&self, _ctx: Context, guild: Guild) {
+    fn guild_create(&self, ctx: Context, guild: Guild) {
         let mut role_cache = self.move_role_cache.write();
         let mut chan_role_cache = self.create_chan_role_cache.write();
-        for (.., role) in guild.roles {
-            Self::update_role_raw(&mut role_cache, &mut chan_role_cache, &role);
+        for role in guild.roles.values() {
+            Self::update_role_raw(&mut role_cache, &mut chan_role_cache, role);
+        }
+        drop(role_cache);
+        drop(chan_role_cache);
+        let first_seen = self.guild_owner_cache.write().insert(guild.id, guild.owner_id).is_none();
+        for channel in guild.channels.values() {
+            let channel = channel.read();
+            self.update_marked_channel(&channel);
+        }
+        self.resync_voice(&ctx, guild.id, &guild.voice_states, first_seen);
+        if self.unswept_guilds.write().remove(&guild.id) {
+            self.catch_up_guild(&ctx, &guild);
         }
-        self.guild_owner_cache.write().insert(guild.id, guild.owner_id);
+    }
+
+    // The bot's own channels are indexed as they're made, so there's only configuration to see to.
+    fn channel_create(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
+        let channel = channel.read();
+        self.update_marked_channel(&channel);
+    }
+
+    fn channel_update(&self, ctx: Context, new: Channel) {
+        match new {
+            Channel::Guild(channel) => {
+                let channel = channel.read();
+                self.update_marked_channel(&channel);
+                if let Some(key) = self.index_channel(&channel) {
+                    self.party_channel_lost(&ctx, key, &channel.name, "moved out of the party");
+                }
+                // Flat parties are keyed by their VC.
+                self.party_renamed(&ctx, channel.id, &channel.name);
+            }
+            Channel::Category(category) => {
+                let category = category.read();
+                self.party_renamed(&ctx, category.id, &category.name);
+            }
+            _ => {}
+        }
+    }
+
+    fn category_delete(&self, ctx: Context, category: Arc<RwLock<ChannelCategory>>) {
+        let category = category.read();
+        let found = match self.party_cache.write().get_mut(&category.id) {
+            Some(party) if party.has_category => {
+                // It's gone already, so teardown mustn't try to delete it again.
+                party.has_category = false;
+                true
+            }
+            _ => false,
+        };
+        if found {
+            info!(party = %category.id, "Party category deleted outside the bot");
+            self.teardown_party(&ctx, category.id, &format!("Its category {} was deleted", category.name));
+        }
+    }
+
+    fn channel_delete(&self, ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
+        let channel = channel.read();
+        if let Some(key) = self.unindex_channel(&channel) {
+            self.party_channel_lost(&ctx, key, &channel.name, "deleted");
+        }
+        let mut lfg_channels = self.lfg_channel_cache.write();
+        if lfg_channels.get(&channel.guild_id) == Some(&channel.id) {
+            lfg_channels.remove(&channel.guild_id);
+        }
+        let mut thread_channels = self.thread_channel_cache.write();
+        if thread_channels.get(&channel.guild_id).map(|&(id, _)| id) == Some(channel.id) {
+            thread_channels.remove(&channel.guild_id);
+        }
+        let mut log_channels = self.log_channel_cache.write();
+        if log_channels.get(&channel.guild_id) == Some(&channel.id) {
+            log_channels.remove(&channel.guild_id);
+        }
+    }
+
+    fn shard_stage_update(&self, _ctx: Context, update: ShardStageUpdateEvent) {
+        let connected = matches!(update.new, ConnectionStage::Connected);
+        info!(shard = ?update.shard_id, stage = ?update.new, "Shard stage changed");
+        self.health.gateway_connected.store(connected, Ordering::SeqCst);
+    }
+
+    fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        let span = info_span!(
+            "reaction_add",
+            guild = ?reaction.guild_id,
+            channel = %reaction.channel_id,
+            user = %reaction.user_id,
+        );
+        let _enter = span.enter();
+        self.handle_lfg_reaction(&ctx, &reaction);
+        self.handle_panel_reaction(&ctx, &reaction);
     }
 }
 
@@ -512,11 +1293,54 @@ impl EventHandler for BotEventsDelegator {
             fn guild_role_delete(&self, ctx: Context, guild: GuildId, role: RoleId);
             fn guild_role_update(&self, ctx: Context, guild: GuildId, role: Role);
             fn guild_create(&self, ctx: Context, guild: Guild);
+            fn channel_create(&self, ctx: Context, channel: Arc<RwLock<GuildChannel>>);
+            fn channel_update(&self, ctx: Context, new: Channel);
+            fn channel_delete(&self, ctx: Context, channel: Arc<RwLock<GuildChannel>>);
+            fn category_delete(&self, ctx: Context, category: Arc<RwLock<ChannelCategory>>);
+            fn reaction_add(&self, ctx: Context, reaction: Reaction);
+            fn shard_stage_update(&self, ctx: Context, update: ShardStageUpdateEvent);
         }
     }
 }
 
+// RUST_LOG picks what gets logged (info by default), and LOG_FORMAT=json switches to one
+// JSON object per line for the log pipeline.
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if std::env::var("LOG_FORMAT").map_or(false, |format| format == "json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
+
 fn main() {
+    init_logging();
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let res = match options.command {
+        Command::Run => run(&options),
+        Command::CheckConfig => cli::check_config(&options),
+        Command::Purge(guild) => cli::purge(&options, guild),
+        Command::ExportState(ref dest) => cli::export_state(&options, dest.as_deref()),
+        Command::ImportState(ref src) => cli::import_state(&options, src),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    if options.dry_run {
+        warn!("Dry run: changes to Discord will be logged rather than made");
+    }
     let perms_member: Permissions = Permissions::READ_MESSAGES
         | Permissions::SEND_MESSAGES
         | Permissions::CONNECT
@@ -527,67 +1351,136 @@ fn main() {
         | Permissions::PRIORITY_SPEAKER
         | Permissions::MENTION_EVERYONE; // Only applies to a channel.
 
+    let token = options.load_token().map_err(|e| e.to_string())?;
+    let state_path = state::path();
+    let saved_state = SavedState::load(&state_path)
+        .map_err(|e| format!("Failed to read the state file {}: {}", state_path.display(), e))?;
+
     let bot = Arc::new(Bot {
         perms_member,
         perms_creator,
         cleanup_queue: RwLock::new(FixedVecDeque::new()),
-        voice_counts: Default::default(),
+        voice_members: Default::default(),
         voice_channels: Default::default(),
-        category_cache: RwLock::new(CategoryCache::new(32)),
-        ignore_cache: RwLock::new(LruCache::new(128)),
+        category_cache: Default::default(),
+        party_cache: Default::default(),
         owner_cache: Default::default(),
         ratelimit_cache: RwLock::new(LruCache::new(128)),
         move_role_cache: Default::default(),
         create_chan_role_cache: Default::default(),
         guild_owner_cache: Default::default(),
         whitelist_role_cache: Default::default(),
+        lfg_channel_cache: Default::default(),
+        thread_channel_cache: Default::default(),
+        log_channel_cache: Default::default(),
+        threads: ThreadClient::new(token.expose()),
+        lfg_board: Default::default(),
+        lfg_queue: Default::default(),
+        split_cache: Default::default(),
+        split_orphans: Default::default(),
+        panel_prompts: Default::default(),
+        metrics: Default::default(),
+        health: Default::default(),
+        dry_run: DryRun::new(options.dry_run),
+        import_legacy: options.import_legacy,
+        retries: Default::default(),
+        shutdown: Default::default(),
+        pending_state: RwLock::new(Some(saved_state)),
+        unswept_guilds: Default::default(),
+        state_path,
     });
+    let http_client = Http::new_with_token(token.expose());
+    let retry_http = Http::new_with_token(token.expose());
+    info!("Preparing client");
+    let mut client = Client::new(token.expose(), BotEventsDelegator(Arc::clone(&bot)))
+        .map_err(|e| format!("Failed to create client: {:?}", e))?;
+    info!("Client prepared");
 
-    let mut token = std::env::args().nth(1).expect("No token supplied");
-    if !token.starts_with("Bot ") {
-        token = format!("Bot {}", token);
-    }
-    let http_client = Http::new_with_token(&token);
-    scope(move |s| {
-        println!("Preparing client");
-        let mut client = Client::new(token, BotEventsDelegator(Arc::clone(&bot)))
-            .expect("Failed to create client. Bad token?");
-        println!("Client prepared");
-
-        let guard = s.spawn(move |_| {
-            let mut last = ChannelId(0);
-            loop {
-                println!("Checking for idle channels");
-                sleep(Duration::from_secs(60));
-                let mut cleanup = bot.cleanup_queue.write();
-                let tail = cleanup.front();
-                if let Some(tail) = tail {
-                    println!("Checking {:?}", tail);
-                    if tail.0 == last {
-                        println!("Was the last checked.");
-                        let tail = cleanup.pop_front().unwrap();
-                        // It's safe, I promise. Probably.
-                        let mut counts = bot.voice_counts.write();
-                        if counts.get(&tail.1).copied().unwrap_or(0) == 0 {
-                            println!("Nobody in the channel; Cleaning up.");
-                            counts.remove(&tail.1);
-                            if let Some(txt) = tail.2 {
-                                let _ = txt.delete(&http_client);
-                            }
-                            let _ = tail.1.delete(&http_client);
-                            let _ = tail.0.delete(&http_client);
-                        }
-                        // Clear the owner cache
-                        bot.owner_cache.write().remove_by_left(&tail.1);
-                    } else {
-                        println!("Setting it as the last used.");
-                        last = tail.0;
+    let metrics_bot = Arc::clone(&bot);
+    thread::spawn(move || metrics::serve(metrics_bot));
+
+    // Nothing is ever sent on this; dropping the sender is what tells the threads to stop.
+    let (stop_threads, stop_requested) = bounded::<()>(0);
+    let (cleanup_done, cleanup_finished) = bounded::<()>(1);
+    let (retries_done, retries_finished) = bounded::<()>(1);
+
+    let retry_bot = Arc::clone(&bot);
+    let retry_stop = stop_requested.clone();
+    thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = retry_stop.recv_timeout(Duration::from_secs(1)) {
+            retry_bot.run_retries(&retry_http);
+        }
+        let _ = retries_done.send(());
+    });
+
+    let cleanup_bot = Arc::clone(&bot);
+    thread::spawn(move || {
+        let bot = cleanup_bot;
+        let mut last = ChannelId(0);
+        let mut last_reconcile = Instant::now();
+        let mut suspects = Suspects::default();
+        // Waiting on the channel doubles as the sleep, so shutdown doesn't have to wait it out.
+        while let Err(RecvTimeoutError::Timeout) = stop_requested.recv_timeout(Duration::from_secs(60)) {
+            let span = info_span!("cleanup");
+            let _enter = span.enter();
+            debug!("Checking for idle channels");
+            bot.health.beat();
+            bot.prune_lfg_queues();
+            bot.expire_empty_splits(&http_client);
+            bot.expire_text_parties(&http_client);
+            let mut cleanup = bot.cleanup_queue.write();
+            let tail = cleanup.front();
+            if let Some(&tail) = tail {
+                debug!(party = %tail, "Checking queued party");
+                if tail == last {
+                    cleanup.pop_front();
+                    // It's safe, I promise. Probably.
+                    if bot.party_is_idle(tail, &bot.voice_members.read()) {
+                        info!(party = %tail, "Nobody in the channel; cleaning up");
+                        bot.teardown_party(&http_client, tail, "Nobody joined after creation");
                     }
+                } else {
+                    last = tail;
                 }
             }
-        });
-        client.start().expect("Failed to start the bot.");
-        guard.join().expect("Failed to join guard");
+            drop(cleanup);
+            if last_reconcile.elapsed() >= RECONCILE_INTERVAL {
+                bot.reconcile(&http_client, &mut suspects);
+                last_reconcile = Instant::now();
+            }
+            // Cheap insurance against a crash losing everything since the last shutdown.
+            bot.flush_state();
+        }
+        let _ = cleanup_done.send(());
+    });
+
+    // Stop taking new parties, let the ones being made finish, then take the shards down.
+    // That makes client.start() return below.
+    let signal_bot = Arc::clone(&bot);
+    let shard_manager = Arc::clone(&client.shard_manager);
+    ctrlc::set_handler(move || {
+        info!("Shutting down");
+        signal_bot.shutdown.request();
+        if !signal_bot.shutdown.wait_in_flight(SHUTDOWN_TIMEOUT) {
+            warn!("Gave up waiting for party creations to finish");
+        }
+        shard_manager.lock().shutdown_all();
     })
-    .expect("Failed to start crossbeam scope");
+    .map_err(|e| format!("Failed to set the signal handler: {}", e))?;
+
+    let started = client.start();
+    if let Err(ref e) = started {
+        error!(error = ?e, "Client stopped");
+    }
+    drop(stop_threads);
+    if cleanup_finished.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+        warn!("Gave up waiting for the cleanup thread");
+    }
+    // Whatever's still queued goes into the state file and picks up again next time.
+    if retries_finished.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+        warn!("Gave up waiting for the retry thread");
+    }
+    bot.flush_state();
+    // Exit non-zero so whatever's supervising the bot knows it didn't stop on purpose.
+    started.map_err(|e| format!("The client stopped: {:?}", e))
 }
@@ -0,0 +1,161 @@
+use crate::Bot;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use serenity::model::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_http::{Header, Response, Server};
+use tracing::{error, info};
+
+pub struct Metrics {
+    registry: Registry,
+    pub parties_created: IntCounter,
+    pub parties_deleted: IntCounter,
+    pub party_failures: IntCounterVec,
+    pub ratelimit_rejections: IntCounterVec,
+    pub cleanup_evictions: IntCounter,
+    pub cache_lookups: IntCounterVec,
+    pub active_parties: IntGaugeVec,
+    pub voice_users: IntGaugeVec,
+    pub api_latency: HistogramVec,
+    pub retries: IntCounterVec,
+    pub retry_queue: IntGauge,
+    pub drift_repairs: IntCounterVec,
+    pub last_drift: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        let registry = Registry::new_custom(Some("coordinator".to_string()), None).unwrap();
+        let metrics = Metrics {
+            parties_created: IntCounter::new("parties_created_total", "Parties created").unwrap(),
+            parties_deleted: IntCounter::new("parties_deleted_total", "Parties torn down").unwrap(),
+            party_failures: IntCounterVec::new(
+                Opts::new("party_failures_total", "Failed Discord calls while managing parties"),
+                &["stage"],
+            ).unwrap(),
+            ratelimit_rejections: IntCounterVec::new(
+                Opts::new("ratelimit_rejections_total", "Commands turned away by the rate limit"),
+                &["reason"],
+            ).unwrap(),
+            cleanup_evictions: IntCounter::new(
+                "cleanup_evictions_total",
+                "Parties pushed out of a full cleanup queue",
+            ).unwrap(),
+            cache_lookups: IntCounterVec::new(
+                Opts::new("cache_lookups_total", "Cache lookups by cache and result"),
+                &["cache", "result"],
+            ).unwrap(),
+            active_parties: IntGaugeVec::new(
+                Opts::new("active_parties", "Parties currently tracked"),
+                &["guild", "pinned"],
+            ).unwrap(),
+            voice_users: IntGaugeVec::new(
+                Opts::new("voice_users", "Users in voice"),
+                &["guild"],
+            ).unwrap(),
+            api_latency: HistogramVec::new(
+                HistogramOpts::new("discord_api_latency_seconds", "Discord API call latency"),
+                &["call"],
+            ).unwrap(),
+            retries: IntCounterVec::new(
+                Opts::new("retries_total", "Failed Discord calls handed to the retry queue, by how they ended"),
+                &["call", "result"],
+            ).unwrap(),
+            retry_queue: IntGauge::new("retry_queue", "Discord calls waiting to be retried").unwrap(),
+            drift_repairs: IntCounterVec::new(
+                Opts::new("drift_repairs_total", "Cached state found out of step with Discord and put right, by kind"),
+                &["kind"],
+            ).unwrap(),
+            last_drift: IntGauge::new("reconcile_last_drift", "Repairs made by the last reconciliation run").unwrap(),
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.parties_created.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.parties_deleted.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.party_failures.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.ratelimit_rejections.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.cleanup_evictions.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.cache_lookups.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.active_parties.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.voice_users.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.api_latency.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.retries.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.retry_queue.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.drift_repairs.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.last_drift.clone())).unwrap();
+        metrics
+    }
+}
+
+impl Metrics {
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        self.cache_lookups.with_label_values(&[cache, if hit { "hit" } else { "miss" }]).inc();
+    }
+
+    // Runs a Discord call and records how long it took.
+    pub fn timed<T>(&self, call: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.api_latency.with_label_values(&[call]).observe(start.elapsed().as_secs_f64());
+        res
+    }
+}
+
+impl Bot {
+    fn render_metrics(&self) -> Vec<u8> {
+        // Counting parties at scrape time is cheaper than keeping a gauge in step with every
+        // code path that adds or removes one.
+        let mut per_guild = BTreeMap::<(GuildId, bool), i64>::new();
+        for party in self.party_cache.read().values() {
+            *per_guild.entry((party.guild, party.pinned)).or_insert(0) += 1;
+        }
+        self.metrics.active_parties.reset();
+        for ((guild, pinned), count) in per_guild {
+            self.metrics.active_parties.with_label_values(&[&guild.to_string(), &pinned.to_string()]).set(count);
+        }
+        self.metrics.retry_queue.set(self.retries.depth() as i64);
+
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.metrics.registry.gather(), &mut buffer) {
+            error!(error = ?e, "Failed to encode metrics");
+        }
+        buffer
+    }
+}
+
+pub fn addr() -> String {
+    std::env::var("METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:9100".to_string())
+}
+
+// Serves /metrics, /healthz and /readyz until the process exits. METRICS_ADDR picks where
+// (0.0.0.0:9100 by default).
+pub fn serve(bot: Arc<Bot>) {
+    let addr = addr();
+    let server = match Server::http(&addr) {
+        Ok(server) => server,
+        Err(e) => {
+            error!(addr = %addr, error = ?e, "Failed to start the metrics server");
+            return;
+        }
+    };
+    info!(addr = %addr, "Serving metrics");
+    for request in server.incoming_requests() {
+        let response = match request.url() {
+            "/metrics" => Response::from_data(bot.render_metrics()).with_header(
+                Header::from_bytes(&b"Content-Type"[..], TextEncoder::new().format_type()).unwrap(),
+            ),
+            "/healthz" => {
+                let (status, body) = bot.healthz();
+                Response::from_string(body).with_status_code(status)
+            }
+            "/readyz" => {
+                let (status, body) = bot.readyz();
+                Response::from_string(body).with_status_code(status)
+            }
+            _ => Response::from_string("Not found").with_status_code(404),
+        };
+        let _ = request.respond(response);
+    }
+}
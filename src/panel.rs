@@ -0,0 +1,276 @@
+use crate::audit::AuditEvent;
+use crate::voice::{mention_list, VoiceMembers};
+use crate::{user_id, Bot, PARTY_PREFIX};
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const LOCK: &str = "🔒";
+const LIMIT: &str = "👥";
+const RENAME: &str = "📝";
+const INVITE: &str = "➕";
+const TRANSFER: &str = "👑";
+const DISBAND: &str = "❌";
+const PANEL_EMOJIS: [&str; 6] = [LOCK, LIMIT, RENAME, INVITE, TRANSFER, DISBAND];
+// How long we'll wait for a reply after a panel button that needs more information.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Copy)]
+pub enum PanelAction {
+    Limit,
+    Rename,
+    Invite,
+    Transfer,
+}
+
+impl Bot {
+    fn render_panel(&self, key: ChannelId, voice: &VoiceMembers) -> Option<String> {
+        let members = self.party_members(key, voice);
+        let party_cache = self.party_cache.read();
+        let party = party_cache.get(&key)?;
+        let owner = self.owner_cache.read().get_by_left(&key).map(|&(owner, ..)| owner.mention());
+        let co_owners = party.co_owners.iter().map(|u| u.mention()).collect::<Vec<_>>();
+        Some(format!(
+            "**{}**\nOwner: {}\nCo-owners: {}\nIn voice: {}\nLocked: {}\nLimit: {}\nPinned: {}\n\n\
+            {} lock/unlock · {} set limit · {} rename · {} invite · {} transfer · {} disband",
+            party.name,
+            owner.unwrap_or_else(|| "nobody".to_string()),
+            if co_owners.is_empty() { "none".to_string() } else { co_owners.join(" ") },
+            mention_list(&members),
+            if party.locked { "yes" } else { "no" },
+            party.limit.map_or_else(|| "none".to_string(), |limit| limit.to_string()),
+            if party.pinned { "yes" } else { "no" },
+            LOCK, LIMIT, RENAME, INVITE, TRANSFER, DISBAND,
+        ))
+    }
+
+    // Posts and pins the control panel in the party's text channel (or thread), if it has one.
+    pub fn post_panel(&self, http: impl AsRef<Http>, key: ChannelId) {
+        let surface = match self.party_cache.read().get(&key) {
+            Some(party) => party.thread.or_else(|| party.text.first().copied()),
+            None => return,
+        };
+        let content = self.render_panel(key, &self.voice_members.read());
+        let (surface, content) = match (surface, content) {
+            (Some(surface), Some(content)) => (surface, content),
+            _ => return,
+        };
+        // Same as the LFG board, there'd be no real message to hand back.
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, party = %key, channel = %surface, "Would post control panel");
+            return;
+        }
+        let posted = surface.send_message(&http, |m| {
+            m.content(content)
+                .reactions(PANEL_EMOJIS.iter().map(|&e| ReactionType::Unicode(e.to_string())))
+        });
+        match posted {
+            Ok(message) => {
+                let _ = surface.pin(&http, message.id);
+                if let Some(party) = self.party_cache.write().get_mut(&key) {
+                    party.panel = Some((surface, message.id));
+                }
+            }
+            Err(e) => warn!(party = %key, channel = %surface, error = ?e, "Failed to post control panel"),
+        }
+    }
+
+    // Like refresh_lfg_entry, voice_state_update already has the members locked.
+    pub fn refresh_panel(&self, http: impl AsRef<Http>, key: ChannelId, voice: &VoiceMembers) {
+        let panel = self.party_cache.read().get(&key).and_then(|party| party.panel);
+        let (surface, message) = match panel {
+            Some(panel) => panel,
+            None => return,
+        };
+        if let Some(content) = self.render_panel(key, voice) {
+            self.edit_message(&http, surface, message, content);
+        }
+    }
+
+    pub fn handle_panel_reaction(&self, http: impl AsRef<Http>, reaction: &Reaction) {
+        if reaction.user_id == user_id() {
+            return;
+        }
+        let emoji = match reaction.emoji {
+            ReactionType::Unicode(ref emoji) => emoji.as_str(),
+            _ => return,
+        };
+        let found = self.party_cache.read().iter()
+            .find(|(_, party)| party.panel.map(|(_, message)| message) == Some(reaction.message_id))
+            .map(|(&key, party)| (key, party.guild, party.co_owners.contains(&reaction.user_id)));
+        let (key, guild, is_co_owner) = match found {
+            Some(found) => found,
+            None => return,
+        };
+        self.delete_reaction(&http, reaction);
+        let is_owner = self.owner_cache.read().get_by_left(&key).map_or(false, |&(owner, ..)| owner == reaction.user_id);
+        if !(is_owner || is_co_owner) {
+            return;
+        }
+
+        let prompt = match emoji {
+            LOCK => {
+                let locked = self.party_cache.read().get(&key).map_or(true, |party| party.locked);
+                self.set_party_locked(&http, key, !locked);
+                None
+            }
+            LIMIT => Some((PanelAction::Limit, "reply with the new user limit (0 for none).")),
+            RENAME => Some((PanelAction::Rename, "reply with the new name.")),
+            INVITE => Some((PanelAction::Invite, "mention everyone you want to invite.")),
+            // Only the owner can give the party away or blow it up.
+            TRANSFER if is_owner => Some((PanelAction::Transfer, "mention the new owner.")),
+            DISBAND if is_owner => {
+                self.teardown_party(&http, key, &format!("Disbanded by {}", reaction.user_id.mention()));
+                return;
+            }
+            _ => None,
+        };
+        if let Some((action, text)) = prompt {
+            self.panel_prompts.write().insert((reaction.channel_id, reaction.user_id), (key, action, Instant::now()));
+            let _ = reaction.channel_id.say(&http, format!("{}, {}", reaction.user_id.mention(), text));
+        }
+        self.refresh_panel(&http, key, &self.voice_members.read());
+    }
+
+    // Returns true if the message was the answer to a panel prompt.
+    pub fn handle_panel_prompt(&self, ctx: &Context, message: &Message) -> bool {
+        let prompt = self.panel_prompts.write().remove(&(message.channel_id, message.author.id));
+        let (key, action) = match prompt {
+            Some((key, action, asked)) if asked.elapsed() < PROMPT_TIMEOUT => (key, action),
+            _ => return false,
+        };
+        match action {
+            PanelAction::Limit => match message.content.trim().parse::<u32>() {
+                Ok(limit) if limit < 100 => self.set_party_limit(ctx, key, limit),
+                _ => {
+                    let _ = message.reply(ctx, "That isn't a limit between 0 and 99.");
+                }
+            },
+            PanelAction::Rename => {
+                let name = message.content.trim().chars().take(20).collect::<String>();
+                if name.is_empty() {
+                    let _ = message.reply(ctx, "That isn't a name.");
+                } else {
+                    self.rename_party(ctx, key, name);
+                }
+            }
+            PanelAction::Invite => {
+                for user in &message.mentions {
+                    if let Err(e) = self.grant_party_access(ctx, key, user.id, "invited from the panel", Some(message.author.id)) {
+                        warn!(party = %key, user = %user.id, error = ?e, "Failed to invite user");
+                    }
+                }
+            }
+            PanelAction::Transfer => match message.mentions.first() {
+                Some(user) => {
+                    if let Err(why) = self.transfer_party(ctx, key, user.id, message.author.id) {
+                        let _ = message.reply(ctx, why);
+                    }
+                }
+                None => {
+                    let _ = message.reply(ctx, "You didn't mention anyone.");
+                }
+            },
+        }
+        self.refresh_panel(ctx, key, &self.voice_members.read());
+        true
+    }
+
+    pub fn set_party_locked(&self, http: impl AsRef<Http>, key: ChannelId, locked: bool) {
+        let guild = match self.party_cache.read().get(&key) {
+            Some(party) => party.guild,
+            None => return,
+        };
+        let (allow, deny) = if locked {
+            (Permissions::empty(), self.perms_member)
+        } else {
+            (self.perms_member, Permissions::empty())
+        };
+        let res = self.set_permission(&http, key, &PermissionOverwrite {
+            allow,
+            deny,
+            kind: PermissionOverwriteType::Role(RoleId(guild.0)),
+        });
+        match res {
+            Ok(()) => {
+                if let Some(party) = self.party_cache.write().get_mut(&key) {
+                    party.locked = locked;
+                }
+            }
+            Err(e) => warn!(party = %key, error = ?e, "Failed to change lock"),
+        }
+    }
+
+    // 0 takes the limit off.
+    pub fn set_party_limit(&self, http: impl AsRef<Http>, key: ChannelId, limit: u32) {
+        let voice = match self.party_cache.read().get(&key) {
+            Some(party) => party.voice.clone(),
+            None => return,
+        };
+        for vc in voice {
+            let _ = self.edit_channel(&http, vc, |c| c.user_limit(u64::from(limit)));
+        }
+        if let Some(party) = self.party_cache.write().get_mut(&key) {
+            party.limit = if limit == 0 { None } else { Some(limit) };
+        }
+        self.refresh_lfg_entry(&http, key, &self.voice_members.read());
+    }
+
+    pub fn rename_party(&self, http: impl AsRef<Http>, key: ChannelId, name: String) {
+        let mut party_cache = self.party_cache.write();
+        let party = match party_cache.get_mut(&key) {
+            Some(party) => party,
+            None => return,
+        };
+        if party.has_category {
+            let _ = self.edit_channel(&http, key, |c| c.name(format!("{}{}", PARTY_PREFIX, name)));
+            // Only touch channels that still have the default names.
+            if party.squads == 1 {
+                let _ = self.edit_channel(&http, party.voice[0], |c| c.name(format!("Party: {}", name)));
+            }
+            if party.text.len() == 1 {
+                let _ = self.edit_channel(&http, party.text[0], |c| c.name(format!("party-{}", name)));
+            }
+        } else {
+            let _ = self.edit_channel(&http, key, |c| c.name(format!("{}{}", PARTY_PREFIX, name)));
+        }
+        party.name = name.clone();
+        drop(party_cache);
+        if let Some(entry) = self.lfg_board.write().get_mut(&key) {
+            entry.name = name;
+        }
+        self.refresh_lfg_entry(&http, key, &self.voice_members.read());
+    }
+
+    pub fn transfer_party(&self, http: impl AsRef<Http>, key: ChannelId, new_owner: UserId, by: UserId) -> Result<(), &'static str> {
+        let (guild, pinned) = match self.party_cache.read().get(&key) {
+            Some(party) => (party.guild, party.pinned),
+            None => return Err("That party doesn't exist any more."),
+        };
+        let mut owner_cache = self.owner_cache.write();
+        if owner_cache.contains_right(&(new_owner, guild, pinned)) {
+            return Err(if pinned { "They already have a pinned party." } else { "They already have a party." });
+        }
+        let old_owner = owner_cache.get_by_left(&key).map(|&(owner, ..)| owner);
+        owner_cache.insert(key, (new_owner, guild, pinned));
+        drop(owner_cache);
+
+        let _ = self.set_permission(&http, key, &PermissionOverwrite {
+            allow: self.perms_creator,
+            deny: Permissions::empty(),
+            kind: PermissionOverwriteType::Member(new_owner),
+        });
+        if let Some(party) = self.party_cache.write().get_mut(&key) {
+            party.co_owners.remove(&new_owner);
+            // The old owner keeps their say in things.
+            if let Some(old_owner) = old_owner {
+                party.co_owners.insert(old_owner);
+            }
+        }
+        self.audit_party(&http, key, AuditEvent::OwnerChanged { from: old_owner, to: new_owner, by });
+        self.refresh_lfg_entry(&http, key, &self.voice_members.read());
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use crate::audit::AuditEvent;
+use crate::Bot;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use tracing::info;
+
+impl Bot {
+    // `/party pin [category]` keeps a party standing when it's empty, for groups that want one
+    // that's always there; `/party unpin` makes it an ordinary party again. The owner keeps it
+    // either way, and since pinned parties have their own one-per-owner quota, owning one doesn't
+    // stop anyone making an ordinary party.
+    pub fn pin_command(&self, ctx: &Context, message: &Message, guild: GuildId, pinned: bool) {
+        if !self.may_manage_channels(guild, message) {
+            let _ = message.reply(ctx, "You need to be able to manage channels to pin or unpin a party.");
+            return;
+        }
+        let key = match self.party_from_command(message, guild) {
+            Ok(key) => key,
+            Err(why) => {
+                let _ = message.reply(ctx, why);
+                return;
+            }
+        };
+        if let Err(why) = self.set_pinned(key, pinned) {
+            let _ = message.reply(ctx, why);
+            return;
+        }
+        info!(party = %key, pinned, by = %message.author.id, "Changed whether party is pinned");
+        self.audit_party(ctx, key, AuditEvent::Pinned { pinned, by: message.author.id });
+        self.refresh_panel(ctx, key, &self.voice_members.read());
+        if pinned {
+            let _ = message.reply(ctx, "Pinned. It'll stay put even when everyone's left.");
+        } else {
+            // It might have been sitting empty for a while, so give it the usual grace period.
+            if self.party_is_idle(key, &self.voice_members.read()) {
+                self.schedule_cleanup(ctx, key);
+            }
+            let _ = message.reply(ctx, "Unpinned. It'll be cleaned up once it's empty.");
+        }
+    }
+
+    fn set_pinned(&self, key: ChannelId, pinned: bool) -> Result<(), &'static str> {
+        let mut party_cache = self.party_cache.write();
+        let mut owner_cache = self.owner_cache.write();
+        let party = party_cache.get_mut(&key).ok_or("That party doesn't exist any more.")?;
+        if party.pinned == pinned {
+            return Err(if pinned { "It's already pinned." } else { "It isn't pinned." });
+        }
+        // Moving to the other quota slot can't push out the owner's other party.
+        if let Some(&(owner, guild, _)) = owner_cache.get_by_left(&key) {
+            if owner_cache.contains_right(&(owner, guild, pinned)) {
+                return Err(if pinned {
+                    "Its owner already has a pinned party."
+                } else {
+                    "Its owner already has an ordinary party. Transfer one of them first."
+                });
+            }
+            owner_cache.insert(key, (owner, guild, pinned));
+        }
+        party.pinned = pinned;
+        Ok(())
+    }
+}
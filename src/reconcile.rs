@@ -0,0 +1,303 @@
+use crate::Bot;
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+// How often the cleanup thread checks everything against Discord.
+pub const RECONCILE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+type GuildChannels = BTreeMap<GuildId, HashMap<ChannelId, GuildChannel>>;
+// (key, guild, has_category, every channel), as they were before the listings were fetched.
+type PartyChannels = Vec<(ChannelId, GuildId, bool, Vec<ChannelId>)>;
+
+// What a reconciliation had to put right, by kind.
+#[derive(Default)]
+struct DriftReport {
+    found: BTreeMap<&'static str, u64>,
+}
+
+impl DriftReport {
+    fn add(&mut self, kind: &'static str, n: u64) {
+        if n > 0 {
+            *self.found.entry(kind).or_insert(0) += n;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.found.values().sum()
+    }
+}
+
+// Things that look left over but might just be half made, carried from one run to the next.
+// Only what's still left over a whole interval later gets dealt with.
+#[derive(Default)]
+pub struct Suspects {
+    owners: BTreeSet<ChannelId>,
+}
+
+impl Bot {
+    // Missed gateway events (a reconnect rather than a resume, mostly) leave the caches out of
+    // step with Discord, and then parties get left behind or deleted with people in them. This
+    // goes over everything with fresh channel listings and fixes what it finds. Voice states
+    // can't be had over REST, so voice is only checked against itself and the channels here;
+    // guild_create brings the real thing.
+    pub fn reconcile(&self, http: impl AsRef<Http>, suspects: &mut Suspects) {
+        let mut report = DriftReport::default();
+        // Anything made while the listings are being fetched won't be in them, so only what was
+        // known beforehand can be found missing.
+        let voice_before = self.voice_channels.read().clone();
+        let parties = self.party_cache.read().iter()
+            .map(|(&key, party)| (key, party.guild, party.has_category, party.voice.iter().chain(&party.text).copied().collect()))
+            .collect::<PartyChannels>();
+        let guilds = self.guild_owner_cache.read().keys().copied().collect::<Vec<_>>();
+        let mut channels = GuildChannels::new();
+        let mut complete = true;
+        for guild in guilds {
+            match guild.channels(&http) {
+                Ok(found) => {
+                    channels.insert(guild, found);
+                }
+                Err(e) => {
+                    warn!(guild = %guild, error = ?e, "Failed to get channels to reconcile");
+                    complete = false;
+                }
+            }
+        }
+        self.reconcile_voice(&channels, &voice_before, complete, &mut report);
+        self.reconcile_parties(&http, &channels, parties, &mut report);
+        self.reconcile_caches(&http, &mut suspects.owners, &mut report);
+        self.reconcile_orphans(&http, &channels, complete, &mut report);
+        self.queue_idle_parties(&http, &mut report);
+        self.metrics.last_drift.set(report.total() as i64);
+        self.publish_drift("reconcile", report);
+    }
+
+    // Every member set should agree with voice_channels and be for a channel that exists, and
+    // everyone in voice_channels should be in a set.
+    fn reconcile_voice(
+        &self,
+        channels: &GuildChannels,
+        before: &BTreeMap<UserId, ChannelId>,
+        complete: bool,
+        report: &mut DriftReport,
+    ) {
+        let mut member_map = self.voice_channels.write();
+        let mut voice_members = self.voice_members.write();
+        let mut located = BTreeMap::new();
+        for (&guild, guild_channels) in channels {
+            for (chan, user) in voice_members.entries(guild) {
+                let gone = !guild_channels.contains_key(&chan) && before.get(&user) == Some(&chan);
+                if gone && member_map.get(&user) == Some(&chan) {
+                    member_map.remove(&user);
+                }
+                if gone || member_map.get(&user) != Some(&chan) {
+                    voice_members.leave(guild, chan, user);
+                    report.add("voice member", 1);
+                }
+            }
+            located.extend(guild_channels.keys().map(|&chan| (chan, guild)));
+        }
+        member_map.retain(|&user, &mut chan| match located.get(&chan) {
+            Some(&guild) => {
+                if !voice_members.members(guild, chan).map_or(false, |members| members.contains(&user)) {
+                    voice_members.join(guild, chan, user);
+                    report.add("voice member", 1);
+                }
+                true
+            }
+            // Only gone for sure if every guild could be listed.
+            None if complete && before.get(&user) == Some(&chan) => {
+                report.add("voice member", 1);
+                false
+            }
+            None => true,
+        });
+        for &guild in channels.keys() {
+            self.metrics.voice_users.with_label_values(&[&guild.to_string()]).set(voice_members.guild_total(guild) as i64);
+        }
+    }
+
+    // Channels deleted or dragged out of a party without the bot hearing about it, and owners
+    // who've lost their overwrite on the party.
+    fn reconcile_parties(&self, http: impl AsRef<Http>, channels: &GuildChannels, parties: PartyChannels, report: &mut DriftReport) {
+        for (key, guild, has_category, members) in parties {
+            let guild_channels = match channels.get(&guild) {
+                Some(guild_channels) => guild_channels,
+                None => continue,
+            };
+            let key_channel = match guild_channels.get(&key) {
+                Some(key_channel) => key_channel,
+                None => {
+                    report.add("party channel", 1);
+                    self.teardown_party(&http, key, "Its channels were deleted while the bot wasn't watching");
+                    continue;
+                }
+            };
+            for chan in members {
+                let (name, how) = match guild_channels.get(&chan) {
+                    None => (chan.mention(), "deleted"),
+                    Some(c) if has_category && c.category_id != Some(key) => (c.name.clone(), "moved out of the party"),
+                    Some(_) => continue,
+                };
+                if !self.party_cache.read().contains_key(&key) {
+                    break;
+                }
+                {
+                    let mut cache = self.category_cache.write();
+                    let mut party_cache = self.party_cache.write();
+                    Self::remove_party_channel(&mut cache, &mut party_cache, key, chan);
+                }
+                report.add("party channel", 1);
+                self.party_channel_lost(&http, key, &name, how);
+            }
+            if !self.party_cache.read().contains_key(&key) {
+                continue;
+            }
+            let owner = match self.owner_cache.read().get_by_left(&key) {
+                Some(&(owner, ..)) => owner,
+                None => continue,
+            };
+            let has_overwrite = key_channel.permission_overwrites.iter().any(|o| {
+                o.kind == PermissionOverwriteType::Member(owner) && o.allow.contains(self.perms_creator)
+            });
+            if !has_overwrite {
+                report.add("owner overwrite", 1);
+                let res = self.set_permission(&http, key, &PermissionOverwrite {
+                    allow: self.perms_creator,
+                    deny: Permissions::empty(),
+                    kind: PermissionOverwriteType::Member(owner),
+                });
+                if let Err(e) = res {
+                    warn!(party = %key, owner = %owner, error = ?e, "Failed to put the owner's permissions back");
+                }
+            }
+        }
+    }
+
+    // category_cache, owner_cache and the LFG board should only know about parties that exist.
+    // Owners are taken before their party is added, so those get an interval's grace.
+    fn reconcile_caches(&self, http: impl AsRef<Http>, suspects: &mut BTreeSet<ChannelId>, report: &mut DriftReport) {
+        let listed = self.lfg_board.read().keys().copied().collect::<Vec<_>>();
+        let (owned, listed) = {
+            let mut cache = self.category_cache.write();
+            let party_cache = self.party_cache.read();
+            let before = cache.len();
+            cache.retain(|vc, key| party_cache.get(key).map_or(false, |party| party.voice.contains(vc)));
+            report.add("stale cache", (before - cache.len()) as u64);
+            for (&key, party) in party_cache.iter() {
+                for &vc in &party.voice {
+                    if cache.insert(vc, key) != Some(key) {
+                        report.add("stale cache", 1);
+                    }
+                }
+            }
+            let owned = self.owner_cache.read().left_values()
+                .filter(|key| !party_cache.contains_key(key))
+                .copied()
+                .collect::<Vec<_>>();
+            let listed = listed.into_iter().filter(|key| !party_cache.contains_key(key)).collect::<Vec<_>>();
+            (owned, listed)
+        };
+        let (stale, fresh) = owned.into_iter().partition::<Vec<_>, _>(|key| suspects.contains(key));
+        *suspects = fresh.into_iter().collect();
+        report.add("stale cache", (stale.len() + listed.len()) as u64);
+        let mut owner_cache = self.owner_cache.write();
+        for key in stale {
+            owner_cache.remove_by_left(&key);
+        }
+        drop(owner_cache);
+        for key in listed {
+            self.remove_lfg_entry(&http, key);
+        }
+    }
+
+    // Splits aren't saved, so a restart mid-split leaves the teams behind. The state file does
+    // record which categories the bot made for splits, and only those get deleted, never
+    // anything an admin happened to name the same way.
+    fn reconcile_orphans(&self, http: impl AsRef<Http>, channels: &GuildChannels, complete: bool, report: &mut DriftReport) {
+        let orphans = self.split_orphans.read().iter().copied().collect::<Vec<_>>();
+        for category in orphans {
+            match channels.values().find(|guild_channels| guild_channels.contains_key(&category)) {
+                Some(guild_channels) => {
+                    report.add("orphan", 1);
+                    for child in guild_channels.values().filter(|c| c.category_id == Some(category)) {
+                        let _ = self.delete_channel(&http, child.id);
+                    }
+                    if self.delete_channel(&http, category).is_err() {
+                        continue;
+                    }
+                }
+                // Only gone for sure if every guild could be listed.
+                None if !complete => continue,
+                None => {}
+            }
+            self.split_orphans.write().remove(&category);
+        }
+    }
+
+    // An empty party that isn't waiting on the cleanup queue missed its last leave. Now the
+    // member sets are right again it gets the usual grace period.
+    fn queue_idle_parties(&self, http: impl AsRef<Http>, report: &mut DriftReport) {
+        let queued = self.cleanup_queue.read().iter().copied().collect::<BTreeSet<_>>();
+        let idle = {
+            let voice = self.voice_members.read();
+            self.party_cache.read().iter()
+                .filter(|&(key, party)| party.last_active.is_none() && party.is_idle(&voice) && !queued.contains(key))
+                .map(|(&key, _)| key)
+                .collect::<Vec<_>>()
+        };
+        report.add("idle party", idle.len() as u64);
+        for key in idle {
+            self.schedule_cleanup(&http, key);
+        }
+    }
+
+    // guild_create carries every voice state in the guild, which is the only full picture
+    // Discord hands out. After a reconnect that's how joins and leaves that went unseen get
+    // caught. `first_seen` is for guilds ready didn't cover, where there's nothing to drift from.
+    pub fn resync_voice(&self, http: impl AsRef<Http>, guild: GuildId, states: &HashMap<UserId, VoiceState>, first_seen: bool) {
+        let mut report = DriftReport::default();
+        {
+            let mut member_map = self.voice_channels.write();
+            let mut voice_members = self.voice_members.write();
+            let recorded = voice_members.entries(guild).into_iter().collect::<BTreeSet<_>>();
+            for &(chan, user) in &recorded {
+                if states.get(&user).and_then(|state| state.channel_id) != Some(chan) {
+                    report.add("voice member", 1);
+                    if member_map.get(&user) == Some(&chan) {
+                        member_map.remove(&user);
+                    }
+                }
+            }
+            voice_members.reset_guild(guild);
+            for (&user, state) in states {
+                if let Some(chan) = state.channel_id {
+                    if !recorded.contains(&(chan, user)) {
+                        report.add("voice member", 1);
+                    }
+                    voice_members.join(guild, chan, user);
+                    member_map.insert(user, chan);
+                }
+            }
+            self.metrics.voice_users.with_label_values(&[&guild.to_string()]).set(voice_members.guild_total(guild) as i64);
+        }
+        if first_seen {
+            return;
+        }
+        self.queue_idle_parties(&http, &mut report);
+        self.publish_drift("guild create", report);
+    }
+
+    fn publish_drift(&self, source: &'static str, report: DriftReport) {
+        if report.total() == 0 {
+            debug!(source, "No drift found");
+            return;
+        }
+        for (kind, &n) in &report.found {
+            self.metrics.drift_repairs.with_label_values(&[kind]).inc_by(n as _);
+        }
+        warn!(source, found = ?report.found, "Caches had drifted from Discord; repaired");
+    }
+}
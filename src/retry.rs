@@ -0,0 +1,346 @@
+use crate::threads::ThreadError;
+use crate::Bot;
+use parking_lot::RwLock;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use serenity::http::{Http, HttpError};
+use serenity::model::prelude::*;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+const BASE_DELAY: Duration = Duration::from_secs(2);
+const MAX_DELAY: Duration = Duration::from_secs(10 * 60);
+const MAX_ATTEMPTS: u32 = 8;
+// Nobody wants to be dragged into a channel minutes after they asked.
+const MAX_MOVE_ATTEMPTS: u32 = 3;
+
+// A Discord mutation that didn't go through the first time. These get written to the state
+// file, so they need to survive serde.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Op {
+    DeleteChannel(ChannelId),
+    EditChannel(ChannelId, Map<String, Value>),
+    MoveMember(GuildId, UserId, ChannelId),
+    SetPermission(ChannelId, PermissionOverwrite),
+    ArchiveThread(ChannelId),
+}
+
+impl Op {
+    // The channel being changed. Moves are about the member rather than the channel.
+    fn channel(&self) -> Option<ChannelId> {
+        match *self {
+            Op::DeleteChannel(chan) | Op::EditChannel(chan, _) | Op::SetPermission(chan, _) | Op::ArchiveThread(chan) => {
+                Some(chan)
+            }
+            Op::MoveMember(..) => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Op::DeleteChannel(_) => "delete channel",
+            Op::EditChannel(..) => "edit channel",
+            Op::MoveMember(..) => "move member",
+            Op::SetPermission(..) => "create permission",
+            Op::ArchiveThread(_) => "archive thread",
+        }
+    }
+
+    fn max_attempts(&self) -> u32 {
+        match self {
+            Op::MoveMember(..) => MAX_MOVE_ATTEMPTS,
+            _ => MAX_ATTEMPTS,
+        }
+    }
+}
+
+pub struct Failure {
+    pub retryable: bool,
+    pub retry_after: Option<Duration>,
+    pub message: String,
+}
+
+// Rate limits, server errors, and not getting an answer at all are worth another go. Serenity
+// already sits out 429s itself, so one that gets this far is treated like a 5xx.
+pub fn is_retryable(e: &serenity::Error) -> bool {
+    match e {
+        serenity::Error::Http(http) => match **http {
+            HttpError::UnsuccessfulRequest(ref res) => {
+                res.status_code == StatusCode::TOO_MANY_REQUESTS || res.status_code.is_server_error()
+            }
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        serenity::Error::Io(_) => true,
+        _ => false,
+    }
+}
+
+impl From<serenity::Error> for Failure {
+    fn from(e: serenity::Error) -> Failure {
+        Failure { retryable: is_retryable(&e), retry_after: None, message: format!("{:?}", e) }
+    }
+}
+
+impl From<ThreadError> for Failure {
+    fn from(e: ThreadError) -> Failure {
+        let message = match e.status {
+            Some(status) => format!("{}: {}", status, e.message),
+            None => e.message.clone(),
+        };
+        Failure { retryable: e.is_retryable(), retry_after: e.retry_after, message }
+    }
+}
+
+struct Pending {
+    op: Op,
+    attempts: u32,
+    next_try: Instant,
+}
+
+// Ops waiting for another go, with exponential backoff between tries. Anything queued for a
+// channel replaces or folds into what's already queued for it, so a flaky API can't pile up
+// a dozen renames of the same VC.
+#[derive(Default)]
+pub struct RetryQueue {
+    pending: RwLock<Vec<Pending>>,
+}
+
+impl RetryQueue {
+    pub fn depth(&self) -> usize {
+        self.pending.read().len()
+    }
+
+    pub fn ops(&self) -> Vec<Op> {
+        self.pending.read().iter().map(|pending| pending.op.clone()).collect()
+    }
+
+    pub fn push(&self, op: Op, attempts: u32, delay: Duration) {
+        self.insert(op, attempts, delay, true);
+    }
+
+    // For an op that's just failed again. Anything queued for the same thing since it was taken
+    // is newer, so that's what stays.
+    fn requeue(&self, op: Op, attempts: u32, delay: Duration) {
+        self.insert(op, attempts, delay, false);
+    }
+
+    fn insert(&self, op: Op, attempts: u32, delay: Duration, newer: bool) {
+        let next_try = Instant::now() + delay;
+        let mut pending = self.pending.write();
+        if let Some(chan) = op.channel() {
+            if pending.iter().any(|p| matches!(p.op, Op::DeleteChannel(c) if c == chan)) {
+                // It's going anyway; nothing else about it matters.
+                return;
+            }
+        }
+        match op {
+            Op::DeleteChannel(chan) => {
+                pending.retain(|p| p.op.channel() != Some(chan) && !matches!(p.op, Op::MoveMember(_, _, c) if c == chan));
+            }
+            Op::EditChannel(chan, ref changes) => {
+                let existing = pending.iter_mut().find(|p| matches!(p.op, Op::EditChannel(c, _) if c == chan));
+                if let Some(existing) = existing {
+                    if let Op::EditChannel(_, ref mut queued) = existing.op {
+                        // Newer values win, and a retry is older than anything queued since it was taken.
+                        for (key, value) in changes {
+                            if newer || !queued.contains_key(key) {
+                                queued.insert(key.clone(), value.clone());
+                            }
+                        }
+                    }
+                    existing.next_try = existing.next_try.min(next_try);
+                    return;
+                }
+            }
+            Op::SetPermission(chan, ref overwrite) => {
+                let same = |p: &Pending| matches!(p.op, Op::SetPermission(c, ref o) if c == chan && o.kind == overwrite.kind);
+                if !newer && pending.iter().any(same) {
+                    return;
+                }
+                pending.retain(|p| !same(p));
+            }
+            Op::MoveMember(guild, user, _) => {
+                let same = |p: &Pending| matches!(p.op, Op::MoveMember(g, u, _) if g == guild && u == user);
+                if !newer && pending.iter().any(same) {
+                    return;
+                }
+                pending.retain(|p| !same(p));
+            }
+            Op::ArchiveThread(thread) => {
+                if pending.iter().any(|p| matches!(p.op, Op::ArchiveThread(t) if t == thread)) {
+                    return;
+                }
+            }
+        }
+        pending.push(Pending { op, attempts, next_try });
+    }
+
+    fn take_due(&self) -> Vec<Pending> {
+        let now = Instant::now();
+        let mut pending = self.pending.write();
+        let (due, waiting) = pending.drain(..).partition(|p| p.next_try <= now);
+        *pending = waiting;
+        due
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    BASE_DELAY.checked_mul(1 << attempts.min(16)).map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+}
+
+impl Bot {
+    // Called by the wrappers in discord.rs when a call fails. Returns true if it's been queued
+    // for another go, in which case the caller can carry on as if it worked.
+    pub fn retry_later(&self, op: Op, failure: Failure) -> bool {
+        if !failure.retryable {
+            return false;
+        }
+        warn!(call = op.name(), error = %failure.message, "Discord call failed; will retry");
+        self.metrics.retries.with_label_values(&[op.name(), "queued"]).inc();
+        let delay = failure.retry_after.unwrap_or_default().max(backoff(0));
+        self.retries.push(op, 1, delay);
+        true
+    }
+
+    fn execute(&self, http: impl AsRef<Http>, op: &Op) -> Result<(), Failure> {
+        self.metrics.timed(op.name(), || match *op {
+            Op::DeleteChannel(chan) => chan.delete(&http).map(|_| ()).map_err(Failure::from),
+            Op::EditChannel(chan, ref changes) => {
+                http.as_ref().edit_channel(chan.0, changes).map(|_| ()).map_err(Failure::from)
+            }
+            Op::MoveMember(guild, user, chan) => guild.move_member(&http, user, chan).map(|_| ()).map_err(Failure::from),
+            Op::SetPermission(chan, ref overwrite) => chan.create_permission(&http, overwrite).map_err(Failure::from),
+            Op::ArchiveThread(thread) => self.threads.archive(thread).map_err(Failure::from),
+        })
+    }
+
+    // Run by the retry thread every second or so.
+    pub fn run_retries(&self, http: impl AsRef<Http>) {
+        for Pending { op, attempts, .. } in self.retries.take_due() {
+            match self.execute(&http, &op) {
+                Ok(()) => {
+                    info!(call = op.name(), attempts, "Retried Discord call went through");
+                    self.metrics.retries.with_label_values(&[op.name(), "succeeded"]).inc();
+                }
+                Err(failure) if failure.retryable && attempts + 1 < op.max_attempts() => {
+                    let delay = failure.retry_after.unwrap_or_default().max(backoff(attempts));
+                    debug!(call = op.name(), attempts, delay = ?delay, error = %failure.message, "Retry failed; backing off");
+                    self.retries.requeue(op, attempts + 1, delay);
+                }
+                Err(failure) => {
+                    error!(call = op.name(), attempts, error = %failure.message, "Giving up on Discord call");
+                    self.metrics.retries.with_label_values(&[op.name(), "abandoned"]).inc();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(pairs: &[(&str, Value)]) -> Map<String, Value> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+    }
+
+    fn overwrite(user: u64) -> PermissionOverwrite {
+        PermissionOverwrite {
+            allow: Permissions::empty(),
+            deny: Permissions::empty(),
+            kind: PermissionOverwriteType::Member(UserId(user)),
+        }
+    }
+
+    #[test]
+    fn edits_to_one_channel_merge() {
+        let queue = RetryQueue::default();
+        let first = changes(&[("name", Value::from("old")), ("user_limit", Value::from(5))]);
+        queue.push(Op::EditChannel(ChannelId(1), first), 1, Duration::from_secs(60));
+        queue.push(Op::EditChannel(ChannelId(1), changes(&[("name", Value::from("new"))])), 1, Duration::from_secs(0));
+        queue.push(Op::EditChannel(ChannelId(2), changes(&[("name", Value::from("other"))])), 1, Duration::from_secs(60));
+        let ops = queue.ops();
+        assert_eq!(ops.len(), 2);
+        match ops[0] {
+            Op::EditChannel(chan, ref merged) => {
+                assert_eq!(chan, ChannelId(1));
+                assert_eq!(merged["name"], "new");
+                assert_eq!(merged["user_limit"], 5);
+            }
+            _ => panic!("expected an edit"),
+        }
+        // The merged edit goes as soon as the newer one would have.
+        let due = queue.take_due();
+        assert_eq!(due.len(), 1);
+        assert!(matches!(due[0].op, Op::EditChannel(c, _) if c == ChannelId(1)));
+    }
+
+    #[test]
+    fn delete_subsumes_other_ops_on_its_channel() {
+        let queue = RetryQueue::default();
+        queue.push(Op::SetPermission(ChannelId(1), overwrite(5)), 1, Duration::from_secs(0));
+        queue.push(Op::MoveMember(GuildId(9), UserId(5), ChannelId(1)), 1, Duration::from_secs(0));
+        queue.push(Op::EditChannel(ChannelId(2), changes(&[("name", Value::from("kept"))])), 1, Duration::from_secs(0));
+        queue.push(Op::DeleteChannel(ChannelId(1)), 1, Duration::from_secs(0));
+        // Anything for a channel that's going anyway is dropped.
+        queue.push(Op::EditChannel(ChannelId(1), changes(&[("name", Value::from("late"))])), 1, Duration::from_secs(0));
+        queue.push(Op::ArchiveThread(ChannelId(1)), 1, Duration::from_secs(0));
+        let ops = queue.ops();
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(ops[0], Op::EditChannel(c, _) if c == ChannelId(2)));
+        assert!(matches!(ops[1], Op::DeleteChannel(c) if c == ChannelId(1)));
+    }
+
+    #[test]
+    fn repeated_move_replaces_the_last() {
+        let queue = RetryQueue::default();
+        queue.push(Op::MoveMember(GuildId(9), UserId(5), ChannelId(1)), 1, Duration::from_secs(0));
+        queue.push(Op::MoveMember(GuildId(9), UserId(6), ChannelId(1)), 1, Duration::from_secs(0));
+        queue.push(Op::MoveMember(GuildId(9), UserId(5), ChannelId(2)), 1, Duration::from_secs(0));
+        let ops = queue.ops();
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(ops[0], Op::MoveMember(_, u, c) if u == UserId(6) && c == ChannelId(1)));
+        assert!(matches!(ops[1], Op::MoveMember(_, u, c) if u == UserId(5) && c == ChannelId(2)));
+    }
+
+    #[test]
+    fn failed_retry_keeps_what_was_queued_since() {
+        let queue = RetryQueue::default();
+        queue.push(Op::EditChannel(ChannelId(1), changes(&[("name", Value::from("old")), ("user_limit", Value::from(5))])), 1, Duration::from_secs(0));
+        queue.push(Op::SetPermission(ChannelId(1), overwrite(5)), 1, Duration::from_secs(0));
+        queue.push(Op::MoveMember(GuildId(9), UserId(5), ChannelId(1)), 1, Duration::from_secs(0));
+        let due = queue.take_due();
+        assert_eq!(due.len(), 3);
+        // Newer calls come in while the retries are out.
+        queue.push(Op::EditChannel(ChannelId(1), changes(&[("name", Value::from("new"))])), 1, Duration::from_secs(60));
+        let mut granted = overwrite(5);
+        granted.allow = Permissions::CONNECT;
+        queue.push(Op::SetPermission(ChannelId(1), granted), 1, Duration::from_secs(60));
+        queue.push(Op::MoveMember(GuildId(9), UserId(5), ChannelId(2)), 1, Duration::from_secs(60));
+        for Pending { op, attempts, .. } in due {
+            queue.requeue(op, attempts + 1, Duration::from_secs(60));
+        }
+        let ops = queue.ops();
+        assert_eq!(ops.len(), 3);
+        match ops[0] {
+            Op::EditChannel(_, ref merged) => {
+                assert_eq!(merged["name"], "new");
+                assert_eq!(merged["user_limit"], 5);
+            }
+            _ => panic!("expected an edit"),
+        }
+        assert!(matches!(ops[1], Op::SetPermission(_, ref o) if o.allow == Permissions::CONNECT));
+        assert!(matches!(ops[2], Op::MoveMember(_, _, c) if c == ChannelId(2)));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_ceiling() {
+        assert_eq!(backoff(0), BASE_DELAY);
+        assert_eq!(backoff(1), BASE_DELAY * 2);
+        assert_eq!(backoff(3), BASE_DELAY * 8);
+        assert_eq!(backoff(MAX_ATTEMPTS + 10), MAX_DELAY);
+        assert_eq!(backoff(u32::MAX), MAX_DELAY);
+    }
+}
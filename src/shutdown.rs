@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Tracks whether we're on the way out, and how many party creations have to finish first.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+// Held for the length of a party creation, rollback included.
+pub struct InFlight<'a>(&'a Shutdown);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // None once shutdown has started, so nothing new gets going.
+    pub fn begin(&self) -> Option<InFlight<'_>> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        if self.is_requested() {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(InFlight(self))
+    }
+
+    // Returns false if there were still creations going when we gave up.
+    pub fn wait_in_flight(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        true
+    }
+}
@@ -0,0 +1,224 @@
+use crate::voice::VoiceMembers;
+use crate::Bot;
+use cmd::Args;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Deliberately not PARTY_PREFIX, so the party cleanup never mistakes the teams for a party.
+pub const SPLIT_PREFIX: &str = "+= ";
+const MAX_TEAMS: usize = 10;
+// How long a split's teams can sit empty before it's given up on. Moves can be retried for a while.
+const EMPTY_SPLIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Teams are plain VCs rather than parties. Everyone in them came from the same channel and is
+// going back to it, so owners, overwrites and panels would only get in the way, and the split
+// has its own lifecycle: regroup ends it, or it goes once every team has emptied.
+pub struct SplitTeams {
+    pub guild: GuildId,
+    pub creator: UserId,
+    pub origin: ChannelId,
+    pub category: ChannelId,
+    pub teams: Vec<ChannelId>,
+    pub created: Instant,
+}
+
+impl Bot {
+    pub fn may_move_members(&self, guild: GuildId, message: &Message) -> bool {
+        let role_cache = self.move_role_cache.read();
+        message.member.as_ref().map_or(false, |m| m.roles.iter().any(|r| role_cache.contains(r)))
+            || self.guild_owner_cache.read().get(&guild) == Some(&message.author.id)
+    }
+
+    pub fn split_command(&self, ctx: &Context, message: &Message, guild: GuildId) {
+        if !self.may_move_members(guild, message) {
+            let _ = message.reply(ctx, "You need to be able to move members to split a channel.");
+            return;
+        }
+        let args = match Args::parse(&message.content[6..]) {
+            Ok(args) => args,
+            Err(_) => {
+                let _ = message.reply(ctx, "Failed to parse command!");
+                return;
+            }
+        };
+        let count = match args.args.get(1).and_then(|n| n.parse::<usize>().ok()) {
+            Some(n) if (2..=MAX_TEAMS).contains(&n) => n,
+            _ => {
+                let _ = message.reply(ctx, format!("Usage: `/party split <2-{}> [balanced|random]`", MAX_TEAMS));
+                return;
+            }
+        };
+        let balanced = match args.args.get(2).map(String::as_str) {
+            None | Some("balanced") => true,
+            Some("random") => false,
+            Some(_) => {
+                let _ = message.reply(ctx, "The mode has to be `balanced` or `random`.");
+                return;
+            }
+        };
+        let origin = match self.voice_channels.read().get(&message.author.id) {
+            Some(&origin) => origin,
+            None => {
+                let _ = message.reply(ctx, "You need to be in the voice channel you want to split.");
+                return;
+            }
+        };
+        // Moving everyone out would empty the party and get it cleaned up, leaving regroup
+        // nowhere to bring them back to.
+        if self.category_cache.read().contains_key(&origin) {
+            let _ = message.reply(ctx, "Party channels can't be split. Split from a regular voice channel instead.");
+            return;
+        }
+        if self.split_cache.read().contains_key(&origin) {
+            let _ = message.reply(ctx, "That channel is already split. Use `/party regroup` first.");
+            return;
+        }
+        let mut players = self.voice_members.read().members(guild, origin)
+            .map_or_else(Vec::new, |members| members.iter().copied().collect::<Vec<_>>());
+        if players.len() < count {
+            let _ = message.reply(ctx, "There aren't enough people in your channel for that many teams.");
+            return;
+        }
+
+        let cat = self.create_channel(ctx, guild, |c| {
+            c.name(format!("{}Teams", SPLIT_PREFIX))
+                .kind(ChannelType::Category)
+                .position(200)
+        });
+        let cat = match cat {
+            Ok(cat) => cat,
+            Err(_) => {
+                let _ = message.reply(ctx, "Failed to create category.");
+                return;
+            }
+        };
+        let mut teams = Vec::with_capacity(count);
+        for i in 1..=count {
+            let vc = self.create_channel(ctx, guild, |c| {
+                c.name(format!("Team {}", i))
+                    .kind(ChannelType::Voice)
+                    .category(cat)
+            });
+            match vc {
+                Ok(vc) => teams.push(vc),
+                Err(_) => {
+                    let _ = message.reply(ctx, "Failed to create the team channels.");
+                    for vc in teams {
+                        let _ = self.delete_channel(ctx, vc);
+                    }
+                    let _ = self.delete_channel(ctx, cat);
+                    return;
+                }
+            }
+        }
+
+        // Balanced deals a shuffled deck so team sizes never differ by more than one.
+        // Random just drops everyone into any team.
+        let mut rng = rand::thread_rng();
+        players.shuffle(&mut rng);
+        let mut moved_any = false;
+        for (i, &player) in players.iter().enumerate() {
+            let team = if balanced { i % count } else { rng.gen_range(0, count) };
+            moved_any |= self.move_member(ctx, guild, player, teams[team]).is_ok();
+        }
+
+        let split = SplitTeams {
+            guild,
+            creator: message.author.id,
+            origin,
+            category: cat,
+            teams,
+            created: Instant::now(),
+        };
+        if !moved_any {
+            self.teardown_split(ctx, &split);
+            let _ = message.reply(ctx, "Couldn't move anyone into the teams, so they've been taken down again.");
+            return;
+        }
+        self.split_cache.write().insert(origin, split);
+        let _ = message.reply(ctx, format!("Split {} players into {} teams. Use `/party regroup` to bring everyone back.", players.len(), count));
+    }
+
+    pub fn regroup_command(&self, ctx: &Context, message: &Message, guild: GuildId) {
+        if !self.may_move_members(guild, message) {
+            let _ = message.reply(ctx, "You need to be able to move members to regroup a channel.");
+            return;
+        }
+        let current = self.voice_channels.read().get(&message.author.id).copied();
+        let split = {
+            let mut splits = self.split_cache.write();
+            // Prefer the split they're standing in, otherwise fall back to one they made.
+            let origin = splits.values()
+                .find(|s| current.map_or(false, |c| s.origin == c || s.teams.contains(&c)))
+                .or_else(|| splits.values().find(|s| s.guild == guild && s.creator == message.author.id))
+                .map(|s| s.origin);
+            match origin.and_then(|origin| splits.remove(&origin)) {
+                Some(split) => split,
+                None => {
+                    let _ = message.reply(ctx, "There's nothing to regroup.");
+                    return;
+                }
+            }
+        };
+        let players = {
+            let voice = self.voice_members.read();
+            split.teams.iter()
+                .filter_map(|&team| voice.members(guild, team))
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        for player in players {
+            let _ = self.move_member(ctx, guild, player, split.origin);
+        }
+        self.teardown_split(ctx, &split);
+    }
+
+    // Returns true if the channel was a team, in which case the party cleanup should leave it alone.
+    // Once every team has emptied out, the whole split goes.
+    pub fn split_team_emptied(&self, http: impl AsRef<Http>, chan: ChannelId, voice: &VoiceMembers) -> bool {
+        let mut splits = self.split_cache.write();
+        let origin = match splits.values().find(|s| s.teams.contains(&chan)) {
+            Some(split) => split.origin,
+            None => return false,
+        };
+        let split = &splits[&origin];
+        let empty = split.teams.iter().all(|&team| voice.is_empty(split.guild, team));
+        if empty {
+            if let Some(split) = splits.remove(&origin) {
+                self.teardown_split(&http, &split);
+            }
+        }
+        true
+    }
+
+    // Splits whose teams never got anyone in them have no leave to notice, so the cleanup thread
+    // checks for them.
+    pub fn expire_empty_splits(&self, http: impl AsRef<Http>) {
+        let expired = {
+            let voice = self.voice_members.read();
+            let mut splits = self.split_cache.write();
+            let origins = splits.values()
+                .filter(|s| s.created.elapsed() > EMPTY_SPLIT_TIMEOUT && s.teams.iter().all(|&team| voice.is_empty(s.guild, team)))
+                .map(|s| s.origin)
+                .collect::<Vec<_>>();
+            origins.into_iter().filter_map(|origin| splits.remove(&origin)).collect::<Vec<_>>()
+        };
+        for split in expired {
+            info!(origin = %split.origin, "Nobody ever joined the split's teams; tearing it down");
+            self.teardown_split(&http, &split);
+        }
+    }
+
+    fn teardown_split(&self, http: impl AsRef<Http>, split: &SplitTeams) {
+        for &team in &split.teams {
+            let _ = self.delete_channel(&http, team);
+        }
+        let _ = self.delete_channel(&http, split.category);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::lfg::LfgEntry;
+use crate::retry::Op;
+use crate::{Bot, CategoryCache, Party};
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::*;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+// Which channels make up which parties, along with what can't be worked out from the channels
+// themselves after a restart: owners, threads, panels, LFG posts, which parties were still
+// waiting on the cleanup queue, split categories the bot made, and Discord calls that hadn't
+// gone through yet.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SavedState {
+    pub parties: Vec<SavedParty>,
+    pub cleanup_queue: Vec<ChannelId>,
+    #[serde(default)]
+    pub pending_ops: Vec<Op>,
+    #[serde(default)]
+    pub splits: Vec<ChannelId>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedParty {
+    key: ChannelId,
+    owner: Option<UserId>,
+    party: Party,
+    #[serde(default)]
+    lfg: Option<LfgEntry>,
+}
+
+// STATE_FILE, or state.json in the working directory.
+pub fn path() -> PathBuf {
+    PathBuf::from(std::env::var("STATE_FILE").unwrap_or_else(|_| "state.json".to_string()))
+}
+
+impl SavedState {
+    pub fn party_count(&self) -> usize {
+        self.parties.len()
+    }
+
+    pub fn contains(&self, key: ChannelId) -> bool {
+        self.parties.iter().any(|saved| saved.key == key)
+    }
+
+    pub fn load(path: &Path) -> io::Result<SavedState> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::from),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(SavedState::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Written to a temporary file first so a crash mid-write can't leave half a file behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+impl Bot {
+    pub fn snapshot_state(&self) -> SavedState {
+        // refresh_lfg_entry takes the board before owner_cache, so it's copied out on its own.
+        let mut board = self.lfg_board.read().clone();
+        // Same order as everywhere else that holds both: party_cache, then owner_cache. Both are
+        // let go before the cleanup queue, which schedule_cleanup takes ahead of party_cache.
+        let parties = {
+            let party_cache = self.party_cache.read();
+            let owners = self.owner_cache.read();
+            party_cache.iter()
+                .map(|(&key, party)| SavedParty {
+                    key,
+                    owner: owners.get_by_left(&key).map(|&(owner, ..)| owner),
+                    party: party.clone(),
+                    lfg: board.remove(&key),
+                })
+                .collect()
+        };
+        let mut splits = self.split_orphans.read().clone();
+        splits.extend(self.split_cache.read().values().map(|split| split.category));
+        SavedState {
+            parties,
+            cleanup_queue: self.cleanup_queue.read().iter().copied().collect(),
+            pending_ops: self.retries.ops(),
+            splits: splits.into_iter().collect(),
+        }
+    }
+
+    pub fn flush_state(&self) {
+        // A dry run's state is full of made-up IDs, so it mustn't replace the real thing.
+        if self.dry_run.is_enabled() {
+            return;
+        }
+        match self.snapshot_state().save(&self.state_path) {
+            Ok(()) => debug!(path = %self.state_path.display(), "Saved state"),
+            Err(e) => warn!(path = %self.state_path.display(), error = ?e, "Failed to save state"),
+        }
+    }
+
+    // Called from ready with every channel in the guilds it saw. The state file is the record of
+    // which channels are parties: anything it lists that's been deleted since is dropped from its
+    // party, and the startup sweep deals with parties left with nothing. `legacy` parties, found
+    // by name, are only taken on if none of their channels already belong to a saved party.
+    pub fn restore_state(
+        &self,
+        seen: &BTreeSet<GuildId>,
+        existing: &BTreeSet<ChannelId>,
+        legacy: Vec<(ChannelId, Party)>,
+        cache: &mut CategoryCache,
+    ) {
+        let saved = self.pending_state.write().take().unwrap_or_default();
+        if self.dry_run.is_enabled() {
+            info!(dry_run = true, count = saved.pending_ops.len(), "Would resume unfinished Discord calls");
+        } else {
+            if !saved.pending_ops.is_empty() {
+                info!(count = saved.pending_ops.len(), "Resuming unfinished Discord calls");
+            }
+            for op in saved.pending_ops {
+                self.retries.push(op, 0, Duration::from_secs(0));
+            }
+        }
+        // Splits don't survive a restart, so whatever the last run left up is for reconcile to
+        // take down.
+        self.split_orphans.write().extend(saved.splits);
+        let mut party_cache = self.party_cache.write();
+        let mut owners = self.owner_cache.write();
+        let mut board = Vec::new();
+        for SavedParty { key, owner, mut party, lfg } in saved.parties {
+            // A text-only party's clock restarts with the bot; there's nothing better to go on.
+            if party.voice.is_empty() {
+                party.last_active = Some(Instant::now());
+            }
+            // Guilds that were unavailable at ready can't say what's gone yet; guild_create
+            // catches them up.
+            if seen.contains(&party.guild) {
+                forget_deleted(key, &mut party, existing);
+            }
+            for &vc in &party.voice {
+                cache.insert(vc, key);
+            }
+            if let Some(owner) = owner {
+                owners.insert(key, (owner, party.guild, party.pinned));
+            }
+            if let Some(entry) = lfg {
+                board.push((key, entry));
+            }
+            party_cache.insert(key, party);
+        }
+        let claimed = party_cache.iter()
+            .flat_map(|(&key, party)| std::iter::once(key).chain(party.voice.iter().copied()).chain(party.text.iter().copied()))
+            .collect::<BTreeSet<_>>();
+        for (key, party) in legacy {
+            if claimed.contains(&key) || party.voice.iter().chain(&party.text).any(|chan| claimed.contains(chan)) {
+                continue;
+            }
+            info!(party = %key, name = %party.name, "Imported legacy party");
+            for &vc in &party.voice {
+                cache.insert(vc, key);
+            }
+            party_cache.insert(key, party);
+        }
+        let requeue = saved.cleanup_queue.into_iter()
+            .filter(|key| party_cache.contains_key(key))
+            .collect::<Vec<_>>();
+        // schedule_cleanup takes the queue before party_cache, so let go of the parties first.
+        drop(owners);
+        drop(party_cache);
+        let mut queue = self.cleanup_queue.write();
+        for key in requeue {
+            if !queue.is_full() {
+                *queue.push_back() = key;
+            }
+        }
+        drop(queue);
+        self.lfg_board.write().extend(board);
+        self.health.state_loaded.store(true, Ordering::SeqCst);
+    }
+}
+
+// Drops whatever's no longer in `existing` from a party, leaving the sweep to decide whether
+// what's left is still worth keeping.
+pub fn forget_deleted(key: ChannelId, party: &mut Party, existing: &BTreeSet<ChannelId>) {
+    let squads = party.voice.iter().take(party.squads).filter(|vc| existing.contains(vc)).count();
+    party.voice.retain(|vc| existing.contains(vc));
+    party.squads = squads;
+    party.text.retain(|txt| existing.contains(txt));
+    party.has_category &= existing.contains(&key);
+}
@@ -0,0 +1,125 @@
+use crate::Bot;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::StatusCode;
+use serde_json::json;
+use serenity::model::prelude::*;
+use std::time::Duration;
+use tracing::warn;
+
+// Serenity speaks API v6, which predates threads, so these go straight to the REST API.
+const API_BASE: &str = "https://discord.com/api/v9";
+const PRIVATE_THREAD: u8 = 12;
+
+// Parties in a guild with a "+#threads" channel get a private thread in it instead of text
+// channels. "+#threads-flat" goes further and drops the category too.
+pub const THREADS_MARKER: &str = "+#threads";
+pub const THREADS_FLAT_MARKER: &str = "+#threads-flat";
+
+#[derive(Debug)]
+pub struct ThreadError {
+    pub status: Option<StatusCode>,
+    pub retry_after: Option<Duration>, // From Discord's Retry-After header on a 429
+    pub message: String,
+}
+
+impl ThreadError {
+    // No status means the request never got an answer, which is as worth retrying as a 5xx.
+    pub fn is_retryable(&self) -> bool {
+        self.status.map_or(true, |status| status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+    }
+}
+
+pub struct ThreadClient {
+    client: Client,
+    token: String,
+}
+
+impl ThreadClient {
+    pub fn new(token: &str) -> ThreadClient {
+        ThreadClient {
+            client: Client::new(),
+            token: token.to_string(),
+        }
+    }
+
+    pub fn create_private(&self, parent: ChannelId, name: &str) -> Option<ChannelId> {
+        let res = self.client
+            .post(&format!("{}/channels/{}/threads", API_BASE, parent))
+            .header("Authorization", &self.token)
+            .json(&json!({
+                "name": name,
+                "type": PRIVATE_THREAD,
+                "auto_archive_duration": 1440,
+                "invitable": false,
+            }))
+            .send()
+            .and_then(|res| res.error_for_status())
+            .and_then(|res| res.json::<serde_json::Value>());
+        match res {
+            Ok(thread) => thread["id"].as_str().and_then(|id| id.parse().ok()).map(ChannelId),
+            Err(e) => {
+                warn!(channel = %parent, error = ?e, "Failed to create thread");
+                None
+            }
+        }
+    }
+
+    fn send(&self, req: RequestBuilder) -> Result<(), ThreadError> {
+        let res = req.header("Authorization", &self.token).send().map_err(|e| ThreadError {
+            status: None,
+            retry_after: None,
+            message: e.to_string(),
+        })?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = res.headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<f64>().ok())
+            .map(Duration::from_secs_f64);
+        Err(ThreadError {
+            status: Some(status),
+            retry_after,
+            message: res.text().unwrap_or_default(),
+        })
+    }
+
+    pub fn add_member(&self, thread: ChannelId, user: UserId) -> Result<(), ThreadError> {
+        let res = self.send(self.client
+            .put(&format!("{}/channels/{}/thread-members/{}", API_BASE, thread, user))
+            .header("Content-Length", "0"));
+        if let Err(ref e) = res {
+            warn!(thread = %thread, user = %user, error = ?e, "Failed to add user to thread");
+        }
+        res
+    }
+
+    // Archived and locked rather than deleted, so there's still a record of the party.
+    pub fn archive(&self, thread: ChannelId) -> Result<(), ThreadError> {
+        let res = self.send(self.client
+            .patch(&format!("{}/channels/{}", API_BASE, thread))
+            .json(&json!({ "archived": true, "locked": true })));
+        if let Err(ref e) = res {
+            warn!(thread = %thread, error = ?e, "Failed to archive thread");
+        }
+        res
+    }
+}
+
+impl Bot {
+    pub fn update_thread_channel(&self, channel: &GuildChannel) {
+        if channel.kind != ChannelType::Text {
+            return;
+        }
+        let mut thread_channels = self.thread_channel_cache.write();
+        let topic = channel.topic.as_deref().unwrap_or("");
+        if topic.contains(THREADS_MARKER) {
+            let flat = topic.contains(THREADS_FLAT_MARKER);
+            thread_channels.insert(channel.guild_id, (channel.id, flat));
+        } else if thread_channels.get(&channel.guild_id).map(|&(id, _)| id) == Some(channel.id) {
+            thread_channels.remove(&channel.guild_id);
+        }
+    }
+}
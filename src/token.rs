@@ -0,0 +1,139 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Keeps the token out of logs and panic messages; the only way to get at it is expose().
+pub struct Token(String);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(<redacted>)")
+    }
+}
+
+impl Token {
+    // Always has the "Bot " prefix that serenity and the raw REST calls want.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+pub enum TokenError {
+    Missing,
+    Io(&'static str, io::Error),
+    InsecureFile(u32),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Missing => f.write_str(
+                "No token supplied. Set DISCORD_TOKEN, or pass --token-file <path> or --token-stdin",
+            ),
+            TokenError::Io(source, e) => write!(f, "Failed to read the token from {}: {}", source, e),
+            TokenError::InsecureFile(mode) => write!(
+                f,
+                "The token file is accessible to other users (mode {:o}); chmod 600 it",
+                mode & 0o777,
+            ),
+            TokenError::Malformed(why) => write!(f, "That doesn't look like a bot token: {}", why),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn check_file_permissions(path: &Path) -> Result<(), TokenError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path).map_err(|e| TokenError::Io("the token file", e))?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(TokenError::InsecureFile(mode));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_file_permissions(_path: &Path) -> Result<(), TokenError> {
+    Ok(())
+}
+
+// Bot tokens are three base64url segments joined by dots: the user ID, a timestamp, and an HMAC.
+pub fn validate(token: &str) -> Result<(), TokenError> {
+    let segments = token.split('.').collect::<Vec<_>>();
+    if segments.len() != 3 {
+        return Err(TokenError::Malformed("expected three dot-separated parts"));
+    }
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(TokenError::Malformed("one of the parts is empty"));
+    }
+    let base64url = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if !segments.iter().all(|segment| segment.chars().all(base64url)) {
+        return Err(TokenError::Malformed("it contains characters a token can't have"));
+    }
+    Ok(())
+}
+
+fn finish(raw: String) -> Result<Token, TokenError> {
+    let raw = raw.trim();
+    let bare = raw.strip_prefix("Bot ").unwrap_or(raw).trim();
+    if bare.is_empty() {
+        return Err(TokenError::Missing);
+    }
+    validate(bare)?;
+    Ok(Token(format!("Bot {}", bare)))
+}
+
+pub fn from_file(path: &Path) -> Result<Token, TokenError> {
+    check_file_permissions(path)?;
+    finish(fs::read_to_string(path).map_err(|e| TokenError::Io("the token file", e))?)
+}
+
+pub fn from_stdin() -> Result<Token, TokenError> {
+    let mut raw = String::new();
+    io::stdin().read_to_string(&mut raw).map_err(|e| TokenError::Io("stdin", e))?;
+    finish(raw)
+}
+
+pub fn from_env() -> Result<Token, TokenError> {
+    match std::env::var("DISCORD_TOKEN") {
+        Ok(raw) => finish(raw),
+        Err(_) => Err(TokenError::Missing),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shaped like a token, but not one.
+    const FAKE: &str = "MTIzNDU2Nzg5MDEyMzQ1Njc4.AbCdEf.abc-DEF_ghi123";
+
+    #[test]
+    fn bot_prefix_is_added_once() {
+        assert_eq!(finish(FAKE.to_string()).unwrap().expose(), format!("Bot {}", FAKE));
+        assert_eq!(finish(format!("Bot {}\n", FAKE)).unwrap().expose(), format!("Bot {}", FAKE));
+        assert_eq!(finish(format!("  Bot  {}  ", FAKE)).unwrap().expose(), format!("Bot {}", FAKE));
+    }
+
+    #[test]
+    fn empty_is_missing() {
+        assert!(matches!(finish(String::new()), Err(TokenError::Missing)));
+        assert!(matches!(finish("Bot \n".to_string()), Err(TokenError::Missing)));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        for bad in &["no-dots-here", "two.parts", "four.dot.separated.parts", "empty..part", "bad.char$.here", "has.a space.in"] {
+            assert!(matches!(validate(bad), Err(TokenError::Malformed(_))), "{} got through", bad);
+            assert!(matches!(finish(bad.to_string()), Err(TokenError::Malformed(_))), "{} got through", bad);
+        }
+        assert!(validate(FAKE).is_ok());
+    }
+
+    #[test]
+    fn debug_doesnt_show_the_token() {
+        let token = finish(FAKE.to_string()).unwrap();
+        assert!(!format!("{:?}", token).contains(FAKE));
+    }
+}
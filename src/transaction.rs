@@ -0,0 +1,116 @@
+use crate::audit::AuditEvent;
+use crate::Bot;
+use serenity::builder::CreateChannel;
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use std::fmt;
+use tracing::warn;
+
+pub enum CreateError {
+    // Turned away before anything was made.
+    Refused(&'static str),
+    // `stage` failed and everything before it was rolled back; `cleaned_up` is false if some of
+    // that didn't go.
+    Failed { stage: &'static str, cleaned_up: bool },
+}
+
+impl fmt::Display for CreateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreateError::Refused(why) => f.write_str(why),
+            CreateError::Failed { stage, cleaned_up: true } => write!(f, "Failed to {}.", stage),
+            CreateError::Failed { stage, cleaned_up: false } => write!(
+                f,
+                "Failed to {}. Some of the channels made before that couldn't be removed, so an admin may need to tidy up.",
+                stage,
+            ),
+        }
+    }
+}
+
+enum Created {
+    Channel(ChannelId),
+    Thread(ChannelId),
+}
+
+// A party creation in progress. Each step records what it made, so that if a later step fails
+// the whole lot can be taken back down, newest first.
+pub struct Transaction<'a> {
+    bot: &'a Bot,
+    guild: GuildId,
+    created: Vec<Created>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(bot: &'a Bot, guild: GuildId) -> Transaction<'a> {
+        Transaction { bot, guild, created: Vec::new() }
+    }
+
+    fn failed(&self, http: impl AsRef<Http>, stage: &'static str, error: String) -> &'static str {
+        self.bot.audit(http, self.guild, None, AuditEvent::Failed { stage, error });
+        stage
+    }
+
+    // Errors are the stage that failed, ready for rollback().
+    pub fn channel(
+        &mut self,
+        http: impl AsRef<Http>,
+        stage: &'static str,
+        f: impl FnOnce(&mut CreateChannel) -> &mut CreateChannel,
+    ) -> Result<ChannelId, &'static str> {
+        match self.bot.create_channel(&http, self.guild, f) {
+            Ok(chan) => {
+                self.created.push(Created::Channel(chan));
+                Ok(chan)
+            }
+            Err(e) => Err(self.failed(&http, stage, format!("{:?}", e))),
+        }
+    }
+
+    pub fn thread(&mut self, http: impl AsRef<Http>, parent: ChannelId, name: &str) -> Result<ChannelId, &'static str> {
+        match self.bot.create_thread(parent, name) {
+            Some(thread) => {
+                self.created.push(Created::Thread(thread));
+                Ok(thread)
+            }
+            None => Err(self.failed(&http, "create thread", format!("Couldn't create a private thread in {}", parent))),
+        }
+    }
+
+    pub fn thread_member(&mut self, http: impl AsRef<Http>, thread: ChannelId, user: UserId) -> Result<(), &'static str> {
+        if self.bot.add_thread_member(thread, user) {
+            Ok(())
+        } else {
+            Err(self.failed(&http, "add members to the thread", format!("Couldn't add {} to {}", user, thread)))
+        }
+    }
+
+    pub fn rollback(self, http: impl AsRef<Http>, stage: &'static str) -> CreateError {
+        let mut leftovers = Vec::new();
+        for created in self.created.into_iter().rev() {
+            match created {
+                Created::Channel(chan) => {
+                    if let Err(e) = self.bot.delete_channel(&http, chan) {
+                        leftovers.push(format!("{}: {:?}", chan, e));
+                    }
+                }
+                // Threads can't be deleted without Manage Threads, but archived and locked
+                // they're out of the way.
+                Created::Thread(thread) => {
+                    if !self.bot.archive_thread(thread) {
+                        leftovers.push(format!("{}: couldn't archive the thread", thread));
+                    }
+                }
+            }
+        }
+        let cleaned_up = leftovers.is_empty();
+        if !cleaned_up {
+            warn!(guild = %self.guild, stage, leftovers = ?leftovers, "Rollback left channels behind");
+            self.bot.audit(&http, self.guild, None, AuditEvent::Failed {
+                stage: "roll back party creation",
+                error: leftovers.join("\n"),
+            });
+        }
+        CreateError::Failed { stage, cleaned_up }
+    }
+}
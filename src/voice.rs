@@ -0,0 +1,96 @@
+use crate::Bot;
+use serenity::model::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+
+// How many mentions a panel or LFG entry lists before it just gives a number.
+const MAX_LISTED: usize = 10;
+
+// Who's in which voice channel, per guild. Sets rather than counts, so a missed or repeated
+// event can't knock a number out: joining twice is still one member, and leaving a channel
+// you weren't recorded in doesn't take anyone else with you.
+#[derive(Default)]
+pub struct VoiceMembers {
+    guilds: BTreeMap<GuildId, BTreeMap<ChannelId, HashSet<UserId>>>,
+}
+
+impl VoiceMembers {
+    pub fn members(&self, guild: GuildId, chan: ChannelId) -> Option<&HashSet<UserId>> {
+        self.guilds.get(&guild).and_then(|channels| channels.get(&chan))
+    }
+
+    pub fn count(&self, guild: GuildId, chan: ChannelId) -> u32 {
+        self.members(guild, chan).map_or(0, |members| members.len() as u32)
+    }
+
+    pub fn is_empty(&self, guild: GuildId, chan: ChannelId) -> bool {
+        self.count(guild, chan) == 0
+    }
+
+    pub fn join(&mut self, guild: GuildId, chan: ChannelId, user: UserId) {
+        self.guilds.entry(guild).or_default().entry(chan).or_default().insert(user);
+    }
+
+    // Returns true if the channel is empty now.
+    pub fn leave(&mut self, guild: GuildId, chan: ChannelId, user: UserId) -> bool {
+        let channels = match self.guilds.get_mut(&guild) {
+            Some(channels) => channels,
+            None => return true,
+        };
+        let emptied = match channels.get_mut(&chan) {
+            Some(members) => {
+                members.remove(&user);
+                members.is_empty()
+            }
+            None => true,
+        };
+        if emptied {
+            channels.remove(&chan);
+        }
+        emptied
+    }
+
+    // Every (channel, member) pair in the guild, for checking against another source.
+    pub fn entries(&self, guild: GuildId) -> Vec<(ChannelId, UserId)> {
+        self.guilds.get(&guild).map_or_else(Vec::new, |channels| {
+            channels.iter().flat_map(|(&chan, members)| members.iter().map(move |&user| (chan, user))).collect()
+        })
+    }
+
+    // Everyone in voice anywhere in the guild.
+    pub fn guild_total(&self, guild: GuildId) -> usize {
+        self.guilds.get(&guild).map_or(0, |channels| channels.values().map(HashSet::len).sum())
+    }
+
+    // Drops what's known about a guild, ready to be filled in again from a full listing.
+    pub fn reset_guild(&mut self, guild: GuildId) {
+        self.guilds.remove(&guild);
+    }
+}
+
+// "@a @b @c", or "none", with anyone past MAX_LISTED summed up at the end.
+pub fn mention_list(users: &[UserId]) -> String {
+    if users.is_empty() {
+        return "none".to_string();
+    }
+    let mut listed = users.iter().take(MAX_LISTED).map(|u| u.mention()).collect::<Vec<_>>().join(" ");
+    if users.len() > MAX_LISTED {
+        listed.push_str(&format!(" and {} more", users.len() - MAX_LISTED));
+    }
+    listed
+}
+
+impl Bot {
+    // Everyone in any of the party's VCs, in a stable order so rendered lists don't jump about.
+    pub fn party_members(&self, key: ChannelId, voice: &VoiceMembers) -> Vec<UserId> {
+        let mut members = match self.party_cache.read().get(&key) {
+            Some(party) => party.voice.iter()
+                .filter_map(|&vc| voice.members(party.guild, vc))
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        members.sort();
+        members
+    }
+}